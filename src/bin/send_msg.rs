@@ -3,9 +3,11 @@
 //! Example:
 //! ```
 //! cargo run --bin send-msg -- -p /tmp/kvaser.sock -m start.json
+//! cargo run --bin send-msg -- -p /tmp/kvaser.sock devices
+//! cargo run --bin send-msg -- -p /tmp/kvaser.sock status
 //! ```
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UnixStream;
@@ -16,7 +18,8 @@ use kvaser_remotivebus_plugin::msg;
 #[command(
     name = "send-msg",
     version,
-    about = "Send message to remotivebus-kvaser service"
+    about = "Send message to remotivebus-kvaser service",
+    subcommand_negates_reqs = true
 )]
 struct CliArgs {
     #[arg(
@@ -27,38 +30,64 @@ struct CliArgs {
     )]
     plugin_socket_path: String,
 
-    #[arg(short = 'm', long, help = "Path to message JSON to be sent to server")]
-    msg_path: String,
+    #[arg(
+        short = 'm',
+        long,
+        required = true,
+        help = "Path to message JSON to be sent to server"
+    )]
+    msg_path: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// List the Kvaser channels with their device ids
+    Devices,
+    /// Show the running buses with their health
+    Status,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli_args = CliArgs::parse();
-    run(&cli_args.plugin_socket_path, &cli_args.msg_path).await?;
+
+    let msg = match (cli_args.command, cli_args.msg_path) {
+        (Some(CliCommand::Devices), _) => msg::Message::ListDevicesAction,
+        (Some(CliCommand::Status), _) => msg::Message::StatusAction,
+        (None, Some(msg_path)) => read_msg(&msg_path).await?,
+        (None, None) => unreachable!("clap requires a message path without a command"),
+    };
+
+    run(&cli_args.plugin_socket_path, &msg).await?;
     Ok(())
 }
 
-/// Connects to the remotivebus-kvaser server via Unix domain socket and sends the specified message.
-/// The socket must already be created by the server.
-async fn run(plugin_socket_path: &str, msg_path: &str) -> Result<()> {
+async fn read_msg(msg_path: &str) -> Result<msg::Message> {
     let raw_msg = fs::read_to_string(&msg_path)
         .await
         .context(format!("Failed to read {}", msg_path))?;
 
-    let msg: msg::Message =
-        serde_json::from_str(&raw_msg).context("Failed to parse message json")?;
+    serde_json::from_str(&raw_msg).context("Failed to parse message json")
+}
 
+/// Connects to the remotivebus-kvaser server via Unix domain socket and sends the specified message.
+/// The socket must already be created by the server.
+async fn run(plugin_socket_path: &str, msg: &msg::Message) -> Result<()> {
     let mut stream = UnixStream::connect(plugin_socket_path)
         .await
         .context("Failed to connect. Is remotivebus-kvaser server running?")?;
 
-    let bytes = serde_json::to_vec(&msg).context("Failed to serialize message")?;
+    let bytes = serde_json::to_vec(msg).context("Failed to serialize message")?;
 
     stream.write_all(&bytes).await?;
 
-    let mut buf = [0u8; 256];
-    let n = stream.read(&mut buf).await?;
-    println!("{}", String::from_utf8_lossy(&buf[..n]));
+    // Responses such as scan reports can be large, the server closes the socket after replying
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    println!("{}", String::from_utf8_lossy(&response));
 
     Ok(())
 }
@@ -0,0 +1,193 @@
+//! Clocks driving the simulator, either wall-clock time or virtual time for deterministic runs.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::{Interval, interval};
+
+pub trait Clock: Send + Sync {
+    // Time elapsed since the clock was created
+    fn now(&self) -> Duration;
+}
+
+pub struct WallClock {
+    start: Instant,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        WallClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for WallClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Clock that only moves when advanced, so a run is independent of wall-clock time
+#[derive(Default)]
+pub struct VirtualClock {
+    nanos: AtomicU64,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.nanos.fetch_add(d.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// Tick for the worker to read frames, periodic or driven by the reader.
+///
+/// Like `tokio::time::interval`, the first tick completes immediately.
+pub enum Ticker {
+    Wall(Interval),
+    Virtual {
+        clock: Arc<VirtualClock>,
+        period: Duration,
+        started: bool,
+        /// Real time between steps, steps follow each other without waiting if none
+        pace: Option<Interval>,
+    },
+    /// Ticks whenever the reader signals that frames are ready, and at least every period
+    Notified {
+        notify: Arc<Notify>,
+        period: Duration,
+    },
+}
+
+impl Ticker {
+    pub fn wall(period: Duration) -> Self {
+        Ticker::Wall(interval(period))
+    }
+
+    pub fn virtual_time(clock: Arc<VirtualClock>, period: Duration) -> Self {
+        Ticker::Virtual {
+            clock,
+            period,
+            started: false,
+            pace: None,
+        }
+    }
+
+    /// Virtual time running speed times faster than real time, e.g. 1.0 for real time
+    pub fn paced(clock: Arc<VirtualClock>, period: Duration, speed: f64) -> Self {
+        let pace = period.div_f64(speed).max(Duration::from_nanos(1));
+
+        Ticker::Virtual {
+            clock,
+            period,
+            started: false,
+            pace: Some(interval(pace)),
+        }
+    }
+
+    pub fn notified(notify: Arc<Notify>, period: Duration) -> Self {
+        Ticker::Notified { notify, period }
+    }
+
+    pub async fn tick(&mut self) {
+        match self {
+            Ticker::Notified { notify, period } => {
+                tokio::select! {
+                    _ = notify.notified() => {}
+                    _ = tokio::time::sleep(*period) => {}
+                }
+            }
+            Ticker::Wall(interval) => {
+                interval.tick().await;
+            }
+            Ticker::Virtual {
+                clock,
+                period,
+                started,
+                pace,
+            } => {
+                // Unpaced virtual time never waits, give other tasks a chance to run. Time only
+                // moves once the tick completes, so a tick dropped in a select does not skip a
+                // period
+                match pace {
+                    Some(pace) => {
+                        pace.tick().await;
+                    }
+                    None => tokio::task::yield_now().await,
+                }
+
+                if *started {
+                    clock.advance(*period);
+                }
+                *started = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_virtual_ticker_advances_clock() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut ticker = Ticker::virtual_time(clock.clone(), Duration::from_millis(5));
+
+        ticker.tick().await;
+        assert_eq!(clock.now(), Duration::ZERO);
+
+        for _ in 0..1000 {
+            ticker.tick().await;
+        }
+        assert_eq!(clock.now(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_paced_ticker_waits_between_steps() {
+        let clock = Arc::new(VirtualClock::new());
+        // 5 ms of virtual time per real millisecond
+        let mut ticker = Ticker::paced(clock.clone(), Duration::from_millis(5), 5.0);
+
+        let start = Instant::now();
+        for _ in 0..=20 {
+            ticker.tick().await;
+        }
+
+        assert_eq!(clock.now(), Duration::from_millis(100));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_virtual_tick_keeps_time() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut ticker = Ticker::virtual_time(clock.clone(), Duration::from_millis(5));
+        ticker.tick().await;
+
+        tokio::select! {
+            biased;
+            _ = ticker.tick() => panic!("tick should yield first"),
+            _ = std::future::ready(()) => {}
+        }
+        assert_eq!(clock.now(), Duration::ZERO);
+
+        ticker.tick().await;
+        assert_eq!(clock.now(), Duration::from_millis(5));
+    }
+}
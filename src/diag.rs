@@ -0,0 +1,356 @@
+//! LIN 2.x node configuration and identification services, answered on behalf of simulated slaves.
+use crate::ldf;
+use std::collections::HashMap;
+
+/// NAD addressing all slave nodes
+pub const NAD_BROADCAST: u8 = 0x7f;
+const SUPPLIER_ID_WILDCARD: u16 = 0x7fff;
+const FUNCTION_ID_WILDCARD: u16 = 0xffff;
+
+const SID_ASSIGN_NAD: u8 = 0xb0;
+const SID_READ_BY_IDENTIFIER: u8 = 0xb2;
+const SID_CONDITIONAL_CHANGE_NAD: u8 = 0xb3;
+const SID_SAVE_CONFIGURATION: u8 = 0xb6;
+const SID_ASSIGN_FRAME_ID_RANGE: u8 = 0xb7;
+
+const RSID_NEGATIVE: u8 = 0x7f;
+const NRC_SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+
+const IDENTIFIER_PRODUCT_ID: u8 = 0;
+
+/// Frame id value unassigning a configurable frame in AssignFrameIdRange
+const PID_UNASSIGN: u8 = 0x00;
+/// Frame id value keeping a configurable frame unchanged in AssignFrameIdRange
+const PID_DO_NOT_CARE: u8 = 0xff;
+
+struct Node {
+    name: String,
+    nad: u8,
+    initial_nad: u8,
+    product_id: ldf::ProductId,
+    configurable_frames: Vec<String>,
+}
+
+/// Answers diagnostic requests for every LDF slave that has a NAD
+pub struct DiagResponder {
+    nodes: Vec<Node>,
+    // Frame ids changed by AssignFrameIdRange, None when unassigned
+    frame_ids: HashMap<String, Option<u32>>,
+}
+
+impl DiagResponder {
+    pub fn new(ldf: &ldf::LDF) -> Self {
+        let mut nodes: Vec<Node> = ldf
+            .node_attributes
+            .values()
+            .filter_map(|attributes| {
+                let nad = attributes.configured_nad.or(attributes.initial_nad)?;
+
+                Some(Node {
+                    name: attributes.name.clone(),
+                    nad,
+                    initial_nad: attributes.initial_nad.unwrap_or(nad),
+                    product_id: attributes.product_id.unwrap_or_default(),
+                    configurable_frames: attributes.configurable_frames.clone(),
+                })
+            })
+            .collect();
+
+        // Only one node may answer a broadcast, pick it independently of hash order
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        DiagResponder {
+            nodes,
+            frame_ids: HashMap::new(),
+        }
+    }
+
+    /// Current id of frame, taking frame id assignments into account
+    pub fn frame_id(&self, frame: &ldf::Frame) -> Option<u32> {
+        match self.frame_ids.get(&frame.name) {
+            Some(id) => *id,
+            None => Some(frame.id),
+        }
+    }
+
+    /// Handle a MasterReq frame. Returns the SlaveResp payload if a node answers.
+    pub fn handle_request(&mut self, req: &[u8]) -> Option<Vec<u8>> {
+        let &[nad, pci, sid, d1, d2, d3, d4, d5] = req else {
+            log::debug!("Ignoring diagnostic request with length {}", req.len());
+            return None;
+        };
+
+        // Only single frames are used for node configuration
+        if pci >> 4 != 0 {
+            return None;
+        }
+
+        let data = [d1, d2, d3, d4, d5];
+
+        match sid {
+            SID_ASSIGN_NAD => self.assign_nad(nad, sid, data),
+            SID_READ_BY_IDENTIFIER => self.read_by_identifier(nad, sid, data),
+            SID_CONDITIONAL_CHANGE_NAD => self.conditional_change_nad(nad, sid, data),
+            SID_SAVE_CONFIGURATION => {
+                let node = self.node_by_nad(nad)?;
+
+                log::info!("Diagnostics: {} saved its configuration", node.name);
+
+                Some(response(node.nad, sid, &[]))
+            }
+            SID_ASSIGN_FRAME_ID_RANGE => self.assign_frame_id_range(nad, sid, data),
+            _ => {
+                let node = self.node_by_nad(nad)?;
+
+                Some(negative_response(node.nad, sid))
+            }
+        }
+    }
+
+    fn assign_nad(&mut self, nad: u8, sid: u8, data: [u8; 5]) -> Option<Vec<u8>> {
+        let supplier_id = u16::from_le_bytes([data[0], data[1]]);
+        let function_id = u16::from_le_bytes([data[2], data[3]]);
+
+        // Addressed by the initial NAD so a node can be found again after a bad assignment
+        let node = self.nodes.iter_mut().find(|node| {
+            (nad == NAD_BROADCAST || nad == node.initial_nad)
+                && product_matches(&node.product_id, supplier_id, function_id)
+        })?;
+
+        log::info!(
+            "Diagnostics: {} NAD {:#x} assigned {:#x}",
+            node.name,
+            node.nad,
+            data[4]
+        );
+
+        let initial_nad = node.initial_nad;
+        node.nad = data[4];
+
+        Some(response(initial_nad, sid, &[]))
+    }
+
+    fn read_by_identifier(&self, nad: u8, sid: u8, data: [u8; 5]) -> Option<Vec<u8>> {
+        let supplier_id = u16::from_le_bytes([data[1], data[2]]);
+        let function_id = u16::from_le_bytes([data[3], data[4]]);
+
+        let node = self.node_by_nad(nad)?;
+        if !product_matches(&node.product_id, supplier_id, function_id) {
+            return None;
+        }
+
+        match data[0] {
+            IDENTIFIER_PRODUCT_ID => {
+                let product_id = &node.product_id;
+                let [supplier_lsb, supplier_msb] = product_id.supplier_id.to_le_bytes();
+                let [function_lsb, function_msb] = product_id.function_id.to_le_bytes();
+
+                Some(response(
+                    node.nad,
+                    sid,
+                    &[
+                        supplier_lsb,
+                        supplier_msb,
+                        function_lsb,
+                        function_msb,
+                        product_id.variant,
+                    ],
+                ))
+            }
+            _ => Some(negative_response(node.nad, sid)),
+        }
+    }
+
+    fn conditional_change_nad(&mut self, nad: u8, sid: u8, data: [u8; 5]) -> Option<Vec<u8>> {
+        let [identifier, byte, mask, invert, new_nad] = data;
+
+        let index = self.nodes.iter().position(|node| node.nad == nad)?;
+
+        // The condition is evaluated on the ReadByIdentifier response data
+        let identification = match identifier {
+            IDENTIFIER_PRODUCT_ID => {
+                let product_id = &self.nodes[index].product_id;
+                let [supplier_lsb, supplier_msb] = product_id.supplier_id.to_le_bytes();
+                let [function_lsb, function_msb] = product_id.function_id.to_le_bytes();
+
+                [
+                    supplier_lsb,
+                    supplier_msb,
+                    function_lsb,
+                    function_msb,
+                    product_id.variant,
+                ]
+            }
+            _ => return Some(negative_response(nad, sid)),
+        };
+
+        let value = *identification.get(usize::from(byte).checked_sub(1)?)?;
+        if (value ^ invert) & mask != 0 {
+            return None;
+        }
+
+        let node = &mut self.nodes[index];
+
+        log::info!(
+            "Diagnostics: {} NAD {:#x} conditionally changed to {new_nad:#x}",
+            node.name,
+            node.nad
+        );
+
+        node.nad = new_nad;
+
+        Some(response(new_nad, sid, &[]))
+    }
+
+    fn assign_frame_id_range(&mut self, nad: u8, sid: u8, data: [u8; 5]) -> Option<Vec<u8>> {
+        let [start, pids @ ..] = data;
+
+        let node = self.node_by_nad(nad)?;
+
+        let mut assignments = Vec::new();
+        for (i, pid) in pids.iter().enumerate() {
+            let Some(frame_name) = node.configurable_frames.get(usize::from(start) + i) else {
+                continue;
+            };
+
+            match *pid {
+                PID_DO_NOT_CARE => {}
+                PID_UNASSIGN => assignments.push((frame_name.clone(), None)),
+                pid => assignments.push((frame_name.clone(), Some(u32::from(pid & 0x3f)))),
+            }
+        }
+
+        let node_nad = node.nad;
+
+        for (frame_name, id) in assignments {
+            log::info!("Diagnostics: frame {frame_name} assigned id {id:?}");
+
+            self.frame_ids.insert(frame_name, id);
+        }
+
+        Some(response(node_nad, sid, &[]))
+    }
+
+    fn node_by_nad(&self, nad: u8) -> Option<&Node> {
+        self.nodes
+            .iter()
+            .find(|node| nad == NAD_BROADCAST || nad == node.nad)
+    }
+}
+
+fn product_matches(product_id: &ldf::ProductId, supplier_id: u16, function_id: u16) -> bool {
+    (supplier_id == SUPPLIER_ID_WILDCARD || supplier_id == product_id.supplier_id)
+        && (function_id == FUNCTION_ID_WILDCARD || function_id == product_id.function_id)
+}
+
+// Positive single frame response, padded with 0xff
+fn response(nad: u8, sid: u8, data: &[u8]) -> Vec<u8> {
+    let mut msg = vec![nad, 1 + data.len() as u8, sid + 0x40];
+    msg.extend_from_slice(data);
+    msg.resize(8, 0xff);
+    msg
+}
+
+fn negative_response(nad: u8, sid: u8) -> Vec<u8> {
+    vec![
+        nad,
+        0x03,
+        RSID_NEGATIVE,
+        sid,
+        NRC_SUB_FUNCTION_NOT_SUPPORTED,
+        0xff,
+        0xff,
+        0xff,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn new_responder() -> (DiagResponder, ldf::LDF) {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        (DiagResponder::new(&ldf), ldf)
+    }
+
+    #[test]
+    fn test_read_product_id() {
+        let (mut diag, _) = new_responder();
+
+        assert_eq!(
+            diag.handle_request(&[0x81, 0x06, 0xb2, 0x00, 0xff, 0x7f, 0xff, 0xff]),
+            Some(vec![0x81, 0x06, 0xf2, 0x66, 0x00, 0x15, 0x00, 0x01])
+        );
+
+        // Wrong supplier id is not answered, unknown identifiers get a negative response
+        assert_eq!(
+            diag.handle_request(&[0x81, 0x06, 0xb2, 0x00, 0x01, 0x00, 0xff, 0xff]),
+            None
+        );
+        assert_eq!(
+            diag.handle_request(&[0x81, 0x06, 0xb2, 0x20, 0xff, 0x7f, 0xff, 0xff]),
+            Some(vec![0x81, 0x03, 0x7f, 0xb2, 0x12, 0xff, 0xff, 0xff])
+        );
+    }
+
+    #[test]
+    fn test_assign_nad() {
+        let (mut diag, _) = new_responder();
+
+        assert_eq!(
+            diag.handle_request(&[0x86, 0x06, 0xb0, 0x7f, 0x00, 0x04, 0x02, 0x10]),
+            Some(vec![0x86, 0x01, 0xf0, 0xff, 0xff, 0xff, 0xff, 0xff])
+        );
+
+        // The node now answers on its new NAD only
+        assert_eq!(
+            diag.handle_request(&[0x86, 0x01, 0xb6, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            None
+        );
+        assert_eq!(
+            diag.handle_request(&[0x10, 0x01, 0xb6, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            Some(vec![0x10, 0x01, 0xf6, 0xff, 0xff, 0xff, 0xff, 0xff])
+        );
+    }
+
+    #[test]
+    fn test_conditional_change_nad() {
+        let (mut diag, _) = new_responder();
+
+        // Variant (byte 5) of DEVS2 is 1, so the masked value is not zero
+        assert_eq!(
+            diag.handle_request(&[0x81, 0x06, 0xb3, 0x00, 0x05, 0xff, 0x00, 0x20]),
+            None
+        );
+        assert_eq!(
+            diag.handle_request(&[0x81, 0x06, 0xb3, 0x00, 0x05, 0xff, 0x01, 0x20]),
+            Some(vec![0x20, 0x01, 0xf3, 0xff, 0xff, 0xff, 0xff, 0xff])
+        );
+    }
+
+    #[test]
+    fn test_assign_frame_id_range() {
+        let (mut diag, ldf) = new_responder();
+
+        assert_eq!(
+            diag.handle_request(&[0x81, 0x06, 0xb7, 0x00, 0xff, 0x00, 0xff, 0xff]),
+            Some(vec![0x81, 0x01, 0xf7, 0xff, 0xff, 0xff, 0xff, 0xff])
+        );
+        assert_eq!(
+            diag.frame_id(ldf.frames.get("DEVS2LIN01Fr04").unwrap()),
+            None
+        );
+
+        diag.handle_request(&[0x81, 0x06, 0xb7, 0x01, 0x8b, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            diag.frame_id(ldf.frames.get("DEVS2LIN01Fr04").unwrap()),
+            Some(0x0b)
+        );
+        assert_eq!(
+            diag.frame_id(ldf.frames.get("DEVMLIN01Fr01").unwrap()),
+            Some(ldf.frames.get("DEVMLIN01Fr01").unwrap().id)
+        );
+    }
+}
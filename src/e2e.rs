@@ -0,0 +1,282 @@
+//! End-to-end protection of simulated frames, alive counters and checksums.
+use crate::ldf;
+use crate::msg::{ChecksumAlgorithm, E2eRule};
+use anyhow::Result;
+
+pub struct E2eProtector {
+    protections: Vec<Protection>,
+}
+
+struct Protection {
+    frame_id: u32,
+    counter: Option<Counter>,
+    checksum: Option<Checksum>,
+}
+
+struct Counter {
+    offset: u8,
+    size: u8,
+    wrap: u64,
+    value: u64,
+}
+
+struct Checksum {
+    offset: u8,
+    size: u8,
+    algorithm: ChecksumAlgorithm,
+    bytes: Option<Vec<usize>>,
+}
+
+impl E2eProtector {
+    /// Resolve rules against the LDF, failing on unknown frames or signals
+    pub fn new(rules: &[E2eRule], ldf: &ldf::LDF) -> Result<Self> {
+        let protections = rules
+            .iter()
+            .map(|rule| {
+                let mut frames = ldf.frames.values().filter(|f| f.id == rule.frame_id);
+                let frame = frames.next().ok_or(anyhow::anyhow!(
+                    "E2E frame id {:#x} not found in LDF",
+                    rule.frame_id
+                ))?;
+                if let Some(other) = frames.next() {
+                    return Err(anyhow::anyhow!(
+                        "E2E frame id {:#x} is ambiguous in LDF, used by {} and {}",
+                        rule.frame_id,
+                        frame.name,
+                        other.name
+                    ));
+                }
+
+                let counter = match &rule.counter {
+                    Some(counter) => {
+                        let (offset, size) = signal_position(ldf, frame, &counter.signal)?;
+                        let wrap = match counter.wrap {
+                            Some(wrap) => wrap,
+                            None => 1u64.checked_shl(size.into()).ok_or(anyhow::anyhow!(
+                                "E2E counter {} of {size} bits needs an explicit wrap",
+                                counter.signal
+                            ))?,
+                        };
+
+                        if wrap == 0 {
+                            return Err(anyhow::anyhow!(
+                                "E2E counter {} wrap must be positive",
+                                counter.signal
+                            ));
+                        }
+
+                        Some(Counter {
+                            offset,
+                            size,
+                            wrap,
+                            value: 0,
+                        })
+                    }
+                    None => None,
+                };
+
+                let checksum = match &rule.checksum {
+                    Some(checksum) => {
+                        let (offset, size) = signal_position(ldf, frame, &checksum.signal)?;
+
+                        Some(Checksum {
+                            offset,
+                            size,
+                            algorithm: checksum.algorithm,
+                            bytes: checksum.bytes.clone(),
+                        })
+                    }
+                    None => None,
+                };
+
+                Ok(Protection {
+                    frame_id: rule.frame_id,
+                    counter,
+                    checksum,
+                })
+            })
+            .collect::<Result<Vec<Protection>>>()?;
+
+        Ok(E2eProtector { protections })
+    }
+
+    /// Update counter and checksum signals in the payload of an LDF frame about to be emitted
+    pub fn protect(&mut self, frame_id: u32, msg: &mut [u8]) {
+        let Some(protection) = self.protections.iter_mut().find(|p| p.frame_id == frame_id) else {
+            return;
+        };
+
+        if let Some(counter) = protection.counter.as_mut() {
+            ldf::encode_signal(msg, counter.offset, counter.size, counter.value);
+            counter.value = (counter.value + 1) % counter.wrap;
+        }
+
+        if let Some(checksum) = &protection.checksum {
+            // The checksum signal itself is not covered
+            ldf::encode_signal(msg, checksum.offset, checksum.size, 0);
+
+            let covered: Vec<u8> = match &checksum.bytes {
+                Some(bytes) => bytes.iter().filter_map(|i| msg.get(*i).copied()).collect(),
+                None => msg.to_vec(),
+            };
+
+            let value = compute(checksum.algorithm, &covered);
+            ldf::encode_signal(msg, checksum.offset, checksum.size, value as u64);
+        }
+    }
+}
+
+fn signal_position(ldf: &ldf::LDF, frame: &ldf::Frame, name: &str) -> Result<(u8, u8)> {
+    let frame_signal = frame
+        .signals
+        .iter()
+        .find(|s| s.name == name)
+        .ok_or(anyhow::anyhow!(
+            "E2E signal {name} not found in frame {}",
+            frame.name
+        ))?;
+    let signal = ldf
+        .signals
+        .get(name)
+        .ok_or(anyhow::anyhow!("E2E signal {name} not found in LDF"))?;
+
+    Ok((frame_signal.offset, signal.size))
+}
+
+pub fn compute(algorithm: ChecksumAlgorithm, bytes: &[u8]) -> u8 {
+    match algorithm {
+        ChecksumAlgorithm::Xor => bytes.iter().fold(0, |acc, b| acc ^ b),
+        ChecksumAlgorithm::Sum => bytes.iter().fold(0, |acc: u8, b| acc.wrapping_add(*b)),
+        ChecksumAlgorithm::Crc8 => crc8_sae_j1850(bytes),
+    }
+}
+
+fn crc8_sae_j1850(bytes: &[u8]) -> u8 {
+    let crc = bytes.iter().fold(0xff, |mut crc: u8, b| {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x1d
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    });
+
+    crc ^ 0xff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{ChecksumRule, CounterRule};
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_checksum_algorithms() {
+        let bytes = b"123456789";
+
+        assert_eq!(compute(ChecksumAlgorithm::Xor, bytes), 0x31);
+        assert_eq!(compute(ChecksumAlgorithm::Sum, bytes), 0xdd);
+        assert_eq!(compute(ChecksumAlgorithm::Crc8, bytes), 0x4b);
+    }
+
+    #[test]
+    fn test_protect_counter_and_checksum() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let rule = E2eRule {
+            frame_id: 3,
+            counter: Some(CounterRule {
+                signal: "SomeCntr".to_string(),
+                wrap: Some(3),
+            }),
+            checksum: Some(ChecksumRule {
+                signal: "SomeChks".to_string(),
+                algorithm: ChecksumAlgorithm::Xor,
+                bytes: None,
+            }),
+        };
+        let mut e2e = E2eProtector::new(&[rule], &ldf).unwrap();
+
+        let msgs: Vec<Vec<u8>> = (0..4)
+            .map(|_| {
+                let mut msg = vec![0x00, 0x01];
+                e2e.protect(3, &mut msg);
+                msg
+            })
+            .collect();
+
+        // SomeCntr is bits 2-3 and SomeChks the 2 lowest bits of the xor over both bytes
+        assert_eq!(
+            msgs,
+            vec![
+                vec![0b0000_0001, 0x01],
+                vec![0b0000_0101, 0x01],
+                vec![0b0000_1001, 0x01],
+                vec![0b0000_0001, 0x01],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_signal_fails() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let rule = E2eRule {
+            frame_id: 3,
+            counter: Some(CounterRule {
+                signal: "NoSuchSignal".to_string(),
+                wrap: None,
+            }),
+            checksum: None,
+        };
+
+        assert!(E2eProtector::new(&[rule], &ldf).is_err());
+    }
+
+    #[test]
+    fn test_64_bit_counter_needs_wrap() {
+        let mut ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        ldf.signals.get_mut("SomeCntr").unwrap().size = 64;
+        let rule = |wrap| E2eRule {
+            frame_id: 3,
+            counter: Some(CounterRule {
+                signal: "SomeCntr".to_string(),
+                wrap,
+            }),
+            checksum: None,
+        };
+
+        let err = E2eProtector::new(&[rule(None)], &ldf).err().unwrap();
+        assert!(err.to_string().contains("needs an explicit wrap"), "{err}");
+        assert!(E2eProtector::new(&[rule(Some(16))], &ldf).is_ok());
+    }
+
+    #[test]
+    fn test_ambiguous_frame_id_fails() {
+        let mut ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        ldf.frames.insert(
+            "Duplicate".to_string(),
+            ldf::Frame {
+                name: "Duplicate".to_string(),
+                id: 3,
+                owner: "DEVM".to_string(),
+                size: 1,
+                signals: Vec::new(),
+                comment: None,
+            },
+        );
+        let rule = E2eRule {
+            frame_id: 3,
+            counter: Some(CounterRule {
+                signal: "SomeCntr".to_string(),
+                wrap: None,
+            }),
+            checksum: None,
+        };
+
+        let err = E2eProtector::new(&[rule], &ldf).err().unwrap();
+        assert!(err.to_string().contains("is ambiguous"), "{err}");
+    }
+}
@@ -0,0 +1,261 @@
+//! Fault injection for simulated LIN traffic.
+use crate::frame::{Frame, FrameFlags, FrameInfo};
+use crate::ldf;
+use crate::msg::{Fault, FaultRule};
+
+pub struct FaultInjector {
+    rules: Vec<RuleState>,
+    rng: XorShift,
+}
+
+struct RuleState {
+    rule: FaultRule,
+    matches: usize,
+}
+
+impl FaultInjector {
+    pub fn new(rules: &[FaultRule], seed: u64) -> Self {
+        FaultInjector {
+            rules: rules
+                .iter()
+                .map(|rule| RuleState {
+                    rule: rule.clone(),
+                    matches: 0,
+                })
+                .collect(),
+            rng: XorShift::new(seed),
+        }
+    }
+
+    /// Apply the first triggering rule matching the frame. Returns None if the frame is dropped.
+    pub fn inject(
+        &mut self,
+        ldf: &ldf::LDF,
+        ldf_frame: Option<&ldf::Frame>,
+        slot: Option<usize>,
+        frame: Frame,
+    ) -> Option<Frame> {
+        match self.next_fault(frame.id, slot) {
+            Some(fault) => apply(ldf, ldf_frame, &fault, frame),
+            None => Some(frame),
+        }
+    }
+
+    fn next_fault(&mut self, id: u32, slot: Option<usize>) -> Option<Fault> {
+        for state in self.rules.iter_mut() {
+            if !state.rule_matches(id, slot) {
+                continue;
+            }
+
+            let rule = &state.rule;
+            let occurrence = state.matches;
+            state.matches += 1;
+
+            let triggered = match (&rule.sequence, rule.probability) {
+                (Some(sequence), _) if !sequence.is_empty() => {
+                    sequence[occurrence % sequence.len()]
+                }
+                (_, Some(probability)) => self.rng.next_f64() < probability,
+                _ => true,
+            };
+
+            if triggered {
+                return Some(rule.fault.clone());
+            }
+        }
+
+        None
+    }
+}
+
+impl RuleState {
+    fn rule_matches(&self, id: u32, slot: Option<usize>) -> bool {
+        let id_matches = self.rule.frame_id.is_none_or(|frame_id| frame_id == id);
+        let slot_matches = match self.rule.slot {
+            Some(rule_slot) => slot == Some(rule_slot),
+            None => true,
+        };
+
+        id_matches && slot_matches
+    }
+}
+
+fn apply(
+    ldf: &ldf::LDF,
+    ldf_frame: Option<&ldf::Frame>,
+    fault: &Fault,
+    mut frame: Frame,
+) -> Option<Frame> {
+    log::debug!("Injecting {fault:?} into {frame:?}");
+
+    match fault {
+        Fault::DropResponse => None,
+        Fault::CorruptChecksum => {
+            // The response is transmitted in full with a wrong checksum, receivers flag it
+            let checksum = !frame.expected_checksum();
+            frame.info = Some(FrameInfo {
+                checksum,
+                flags: FrameFlags::RX | FrameFlags::CSUM_ERROR,
+                ..Default::default()
+            });
+            Some(frame)
+        }
+        Fault::HeaderOnly => {
+            // No slave responds, only the header is on the bus
+            frame.msg.clear();
+            frame.info = Some(FrameInfo {
+                flags: FrameFlags::NODATA,
+                ..Default::default()
+            });
+            Some(frame)
+        }
+        Fault::Length(len) => {
+            frame.msg.resize(*len, 0);
+            Some(frame)
+        }
+        Fault::ResponseError => {
+            if let Some((frame_signal, signal)) =
+                ldf_frame.and_then(|f| ldf.response_error_signal(f))
+            {
+                ldf::encode_signal(&mut frame.msg, frame_signal.offset, signal.size, u64::MAX);
+            } else {
+                log::warn!(
+                    "Frame {:#x} does not carry a response_error signal",
+                    frame.id
+                );
+            }
+            Some(frame)
+        }
+    }
+}
+
+/// Small deterministic PRNG (xorshift64*), seeded through splitmix64
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        XorShift((z ^ (z >> 31)) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn rule(frame_id: Option<u32>, fault: Fault) -> FaultRule {
+        FaultRule {
+            frame_id,
+            slot: None,
+            fault,
+            probability: None,
+            sequence: None,
+        }
+    }
+
+    fn frame(id: u32, msg: &[u8]) -> Frame {
+        Frame::new(id, msg.to_vec())
+    }
+
+    #[test]
+    fn test_faults_only_hit_matching_frames() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let mut faults = FaultInjector::new(&[rule(Some(5), Fault::DropResponse)], 0);
+
+        assert_eq!(faults.inject(&ldf, None, None, frame(5, &[1, 2, 3])), None);
+        assert_eq!(
+            faults.inject(&ldf, None, None, frame(3, &[1, 2])),
+            Some(frame(3, &[1, 2]))
+        );
+    }
+
+    #[test]
+    fn test_sequence_triggered_faults() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let mut length_rule = rule(None, Fault::Length(1));
+        length_rule.sequence = Some(vec![false, true]);
+        let mut faults = FaultInjector::new(&[length_rule], 0);
+
+        let lengths: Vec<usize> = (0..4)
+            .filter_map(|_| faults.inject(&ldf, None, None, frame(3, &[1, 2])))
+            .map(|f| f.msg.len())
+            .collect();
+
+        assert_eq!(lengths, vec![2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_probabilistic_faults_are_reproducible() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let mut header_rule = rule(None, Fault::HeaderOnly);
+        header_rule.probability = Some(0.5);
+
+        let run = |seed| {
+            let mut faults = FaultInjector::new(std::slice::from_ref(&header_rule), seed);
+            (0..64)
+                .map(|_| faults.inject(&ldf, None, None, frame(3, &[1])).unwrap())
+                .map(|f| f.msg.is_empty())
+                .collect::<Vec<bool>>()
+        };
+
+        let hits = run(7).iter().filter(|hit| **hit).count();
+        assert_eq!(run(7), run(7));
+        assert!(hits > 0 && hits < 64);
+    }
+
+    #[test]
+    fn test_corrupt_checksum_reports_error() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let mut faults = FaultInjector::new(&[rule(Some(5), Fault::CorruptChecksum)], 0);
+
+        let frame = faults
+            .inject(&ldf, None, None, frame(5, &[1, 2, 3]))
+            .unwrap();
+
+        assert_eq!(frame.msg, vec![1, 2, 3]);
+        assert_eq!(
+            frame.error_report(),
+            Some([5, 0x12, 0, !crate::frame::enhanced_checksum(5, &[1, 2, 3])])
+        );
+    }
+
+    #[test]
+    fn test_header_only_reports_missing_response() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let mut faults = FaultInjector::new(&[rule(Some(5), Fault::HeaderOnly)], 0);
+
+        let frame = faults
+            .inject(&ldf, None, None, frame(5, &[1, 2, 3]))
+            .unwrap();
+
+        assert!(frame.msg.is_empty());
+        assert_eq!(frame.error_report(), Some([5, 0x08, 0, 0]));
+    }
+
+    #[test]
+    fn test_response_error_sets_signal() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let ldf_frame = ldf.frames.get("DEVS1LIN01Fr1");
+        let mut faults = FaultInjector::new(&[rule(Some(5), Fault::ResponseError)], 0);
+
+        assert_eq!(
+            faults.inject(&ldf, ldf_frame, None, frame(5, &[0, 0, 0])),
+            Some(frame(5, &[0, 0, 0b100]))
+        );
+    }
+}
@@ -1,20 +1,239 @@
 use anyhow::{Context, Result};
 use std::fmt;
 
+/// Diagnostic master request frame id
+pub const MASTER_REQ_ID: u32 = 0x3c;
+
+/// Diagnostic slave response frame id
+pub const SLAVE_RESP_ID: u32 = 0x3d;
+
+/// Pseudo frame id used on the vbus for a LIN wake-up pulse, just outside the 6 bit LIN id range
+pub const WAKEUP_ID: u32 = 0x40;
+
+/// Error class of the vbus error frames reporting LIN errors, unused by the CAN error classes
+pub const LIN_ERROR_CLASS: u32 = 0x1000_0000;
+
+/// Error class of the vbus error frame reporting the loss of the LIN interface, `CAN_ERR_BUSOFF`
+pub const BUS_LOST_CLASS: u32 = 0x40;
+
+/// Error class of the vbus error frame reporting the LIN interface is back, `CAN_ERR_RESTARTED`
+pub const BUS_RESTORED_CLASS: u32 = 0x100;
+
+/// Error class of the vbus error frames reporting a change of the bus health, with its code as data.
+/// Error classes are bits inside `CAN_ERR_MASK`, unused by the CAN error classes
+pub const HEALTH_CLASS: u32 = 0x0800_0000;
+
+/// Error class of the vbus error frames reporting the bus went to sleep (data 1) or woke up (data 0)
+pub const SLEEP_CLASS: u32 = 0x0400_0000;
+
 #[derive(Debug, PartialEq)]
 pub struct Packet {
     pub frame: Frame,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub struct Frame {
     pub id: u32,
     pub msg: Vec<u8>,
+    /// Reception details, only known for frames read from a LIN interface
+    pub info: Option<FrameInfo>,
+}
+
+/// Reception details reported by the LIN interface for a frame
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct FrameInfo {
+    /// Hardware timestamp in milliseconds
+    pub timestamp: u64,
+    /// Protected identifier as received
+    pub pid: u8,
+    /// Checksum as received
+    pub checksum: u8,
+    /// Measured bit rate in bits per second
+    pub bitrate: u32,
+    /// Length of the synch break in microseconds
+    pub synch_break_length: u64,
+    /// Length of the whole frame in microseconds
+    pub frame_length: u64,
+    pub flags: FrameFlags,
+}
+
+/// LIN flags of a received frame
+#[derive(Default, PartialEq, Eq, Clone, Copy)]
+pub struct FrameFlags(u32);
+
+impl FrameFlags {
+    /// Transmitted by this node
+    pub const TX: FrameFlags = FrameFlags(1);
+    /// Received from another node
+    pub const RX: FrameFlags = FrameFlags(1 << 1);
+    pub const WAKEUP_FRAME: FrameFlags = FrameFlags(1 << 2);
+    /// Header without response
+    pub const NODATA: FrameFlags = FrameFlags(1 << 3);
+    pub const CSUM_ERROR: FrameFlags = FrameFlags(1 << 4);
+    pub const PARITY_ERROR: FrameFlags = FrameFlags(1 << 5);
+    pub const SYNCH_ERROR: FrameFlags = FrameFlags(1 << 6);
+    pub const BIT_ERROR: FrameFlags = FrameFlags(1 << 7);
+
+    const NAMES: [(FrameFlags, &'static str); 8] = [
+        (FrameFlags::TX, "TX"),
+        (FrameFlags::RX, "RX"),
+        (FrameFlags::WAKEUP_FRAME, "WAKEUP_FRAME"),
+        (FrameFlags::NODATA, "NODATA"),
+        (FrameFlags::CSUM_ERROR, "CSUM_ERROR"),
+        (FrameFlags::PARITY_ERROR, "PARITY_ERROR"),
+        (FrameFlags::SYNCH_ERROR, "SYNCH_ERROR"),
+        (FrameFlags::BIT_ERROR, "BIT_ERROR"),
+    ];
+
+    pub fn empty() -> Self {
+        FrameFlags(0)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: FrameFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Checksum, parity, synch or bit error
+    pub fn is_error(self) -> bool {
+        [
+            FrameFlags::CSUM_ERROR,
+            FrameFlags::PARITY_ERROR,
+            FrameFlags::SYNCH_ERROR,
+            FrameFlags::BIT_ERROR,
+        ]
+        .into_iter()
+        .any(|flag| self.contains(flag))
+    }
+}
+
+impl std::ops::BitOr for FrameFlags {
+    type Output = FrameFlags;
+
+    fn bitor(self, other: FrameFlags) -> FrameFlags {
+        FrameFlags(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for FrameFlags {
+    fn bitor_assign(&mut self, other: FrameFlags) {
+        self.0 |= other.0;
+    }
+}
+
+impl fmt::Debug for FrameFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = FrameFlags::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+
+        write!(f, "{}", names.join(" | "))
+    }
+}
+
+impl Frame {
+    /// Frame as sent on the vbus, without reception details
+    pub fn new(id: u32, msg: Vec<u8>) -> Self {
+        Frame {
+            id,
+            msg,
+            info: None,
+        }
+    }
+
+    /// Go-to-sleep command, a master request with the first data byte 0x00
+    pub fn go_to_sleep() -> Self {
+        Frame::new(
+            MASTER_REQ_ID,
+            vec![0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        )
+    }
+
+    /// Wake-up pulse as seen on the vbus
+    pub fn wakeup() -> Self {
+        Frame::new(WAKEUP_ID, vec![])
+    }
+
+    pub fn is_go_to_sleep(&self) -> bool {
+        self.id == MASTER_REQ_ID && self.msg.first() == Some(&0x00)
+    }
+
+    pub fn is_wakeup(&self) -> bool {
+        self.id == WAKEUP_ID
+    }
+
+    /// Data of the vbus error frame reporting a missing response or a LIN error on this frame:
+    /// frame id, flags, received protected identifier and received checksum
+    pub fn error_report(&self) -> Option<[u8; 4]> {
+        let info = self.info.as_ref()?;
+
+        if !info.flags.is_error() && !info.flags.contains(FrameFlags::NODATA) {
+            return None;
+        }
+
+        Some([
+            self.id as u8,
+            info.flags.bits() as u8,
+            info.pid,
+            info.checksum,
+        ])
+    }
+
+    /// LIN 2.x checksum of the frame, classic for diagnostic frames and enhanced otherwise
+    pub fn expected_checksum(&self) -> u8 {
+        if self.id >= MASTER_REQ_ID {
+            classic_checksum(&self.msg)
+        } else {
+            enhanced_checksum(self.id, &self.msg)
+        }
+    }
+}
+
+/// Protected identifier, the 6 bit frame id with its two parity bits
+pub fn protected_id(id: u32) -> u8 {
+    let bit = |n: u32| (id >> n & 1) as u8;
+
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+
+    (id & 0x3f) as u8 | p0 << 6 | p1 << 7
+}
+
+/// LIN 1.x checksum over the data bytes only
+pub fn classic_checksum(data: &[u8]) -> u8 {
+    checksum(0, data)
+}
+
+/// LIN 2.x checksum, also covering the protected identifier
+pub fn enhanced_checksum(id: u32, data: &[u8]) -> u8 {
+    checksum(protected_id(id), data)
+}
+
+// Inverted sum with carry
+fn checksum(init: u8, data: &[u8]) -> u8 {
+    let sum = data.iter().fold(u16::from(init), |sum, &b| {
+        let sum = sum + u16::from(b);
+        if sum > 0xff { sum - 0xff } else { sum }
+    });
+
+    !(sum as u8)
 }
 
 impl fmt::Debug for Frame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{{ id: {:#x}, msg={:?}}}", self.id, self.msg)
+        match &self.info {
+            Some(info) => write!(
+                f,
+                "{{ id: {:#x}, msg={:?}, info={:?}}}",
+                self.id, self.msg, info
+            ),
+            None => write!(f, "{{ id: {:#x}, msg={:?}}}", self.id, self.msg),
+        }
     }
 }
 
@@ -33,7 +252,7 @@ pub fn parse_packet(packet: &[u8]) -> Result<Packet> {
             msg.truncate(msg_len);
 
             return Ok(Packet {
-                frame: Frame { id, msg },
+                frame: Frame::new(id, msg),
             });
         } else if !msg.is_empty() && msg_len > msg.len() {
             return Err(anyhow::anyhow!(
@@ -44,7 +263,7 @@ pub fn parse_packet(packet: &[u8]) -> Result<Packet> {
         }
 
         Ok(Packet {
-            frame: Frame { id, msg },
+            frame: Frame::new(id, msg),
         })
     } else {
         Err(anyhow::anyhow!(
@@ -72,14 +291,56 @@ mod tests {
         assert_eq!(
             parse_packet(&raw_packet).unwrap(),
             Packet {
-                frame: Frame {
-                    id: 0x31,
-                    msg: vec![10, 20, 30]
-                }
+                frame: Frame::new(0x31, vec![10, 20, 30])
             }
         );
     }
 
+    #[test]
+    fn test_protected_id_and_checksums() {
+        assert_eq!(protected_id(0x00), 0x80);
+        assert_eq!(protected_id(0x3c), 0x3c);
+        assert_eq!(protected_id(0x3d), 0x7d);
+        assert_eq!(protected_id(0x0a), 0xca);
+
+        // Example from the LIN 2.1 specification, with the carry added back
+        assert_eq!(classic_checksum(&[0x4a, 0x55, 0x93, 0xe5]), 0xe6);
+        assert_eq!(enhanced_checksum(0x0a, &[0x55, 0x93, 0xe5]), 0x66);
+    }
+
+    #[test]
+    fn test_frame_flags() {
+        let flags = FrameFlags::RX | FrameFlags::CSUM_ERROR;
+
+        assert!(flags.contains(FrameFlags::RX));
+        assert!(!flags.contains(FrameFlags::NODATA));
+        assert!(flags.is_error());
+        assert!(!(FrameFlags::RX | FrameFlags::NODATA).is_error());
+        assert_eq!(format!("{flags:?}"), "RX | CSUM_ERROR");
+    }
+
+    #[test]
+    fn test_error_report() {
+        let mut frame = Frame::new(0x10, vec![1, 2]);
+        assert_eq!(frame.error_report(), None);
+
+        frame.info = Some(FrameInfo {
+            pid: 0x50,
+            checksum: 0x12,
+            flags: FrameFlags::RX,
+            ..Default::default()
+        });
+        assert_eq!(frame.error_report(), None);
+
+        frame.info = Some(FrameInfo {
+            pid: 0x50,
+            checksum: 0x12,
+            flags: FrameFlags::RX | FrameFlags::CSUM_ERROR,
+            ..Default::default()
+        });
+        assert_eq!(frame.error_report(), Some([0x10, 0x12, 0x50, 0x12]));
+    }
+
     #[test]
     fn test_parse_request_update_frame() {
         let raw_packet: [u8; 16] = [0x31, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
@@ -88,4 +349,18 @@ mod tests {
         assert_eq!(frame.id, 0x31);
         assert!(frame.msg.is_empty());
     }
+
+    #[test]
+    fn test_health_class_survives_error_frame() {
+        let frame = socketcan::CanErrorFrame::new_error(HEALTH_CLASS, &[1]).unwrap();
+
+        assert_eq!(frame.error_bits(), HEALTH_CLASS);
+    }
+
+    #[test]
+    fn test_sleep_class_survives_error_frame() {
+        let frame = socketcan::CanErrorFrame::new_error(SLEEP_CLASS, &[1]).unwrap();
+
+        assert_eq!(frame.error_bits(), SLEEP_CLASS);
+    }
 }
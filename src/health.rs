@@ -0,0 +1,303 @@
+//! Supervision of the health of a LIN bus driven by a hardware interface.
+//!
+//! The health is derived from what the interface reports: a lost interface on an unpowered bus is
+//! `no-power`, a run of synch or bit errors means the bus is held dominant, and a slave seeing no
+//! traffic for a while is `idle`. Changes are reported as bus events and published for status
+//! queries.
+use crate::frame::{Frame, FrameFlags};
+use crate::masterslave::{BusEvent, FrameReader, Master, Slave};
+use crate::msg;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Consecutive synch or bit errors taken as the bus being held dominant
+const STUCK_ERRORS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Health {
+    Healthy = 0,
+    /// The bus has no supply, the interface cannot see it
+    NoPower = 1,
+    /// The bus is held dominant, every header fails
+    Stuck = 2,
+    /// No traffic seen for the idle timeout, slave only
+    Idle = 3,
+    /// The interface is lost for another reason, e.g. unplugged
+    Disconnected = 4,
+}
+
+impl Health {
+    /// Code of the health in vbus error frames
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Health of a bus derived from the frames and events of its interface
+pub struct HealthMonitor {
+    idle_timeout: Option<Duration>,
+    last_traffic: Instant,
+    /// Consecutive synch or bit errors
+    errors: u32,
+    /// Health while the interface is lost
+    lost: Option<Health>,
+    /// A sleeping bus is silent on purpose
+    asleep: bool,
+    state: Health,
+}
+
+impl HealthMonitor {
+    pub fn new(now: Instant, idle_timeout: Option<Duration>) -> Self {
+        HealthMonitor {
+            idle_timeout,
+            last_traffic: now,
+            errors: 0,
+            lost: None,
+            asleep: false,
+            state: Health::Healthy,
+        }
+    }
+
+    pub fn state(&self) -> Health {
+        self.state
+    }
+
+    pub fn observe(&mut self, now: Instant, frame: &Frame) {
+        let dominant = frame.info.as_ref().is_some_and(|info| {
+            info.flags.contains(FrameFlags::SYNCH_ERROR)
+                || info.flags.contains(FrameFlags::BIT_ERROR)
+        });
+
+        if dominant {
+            self.errors += 1;
+        } else {
+            self.errors = 0;
+            self.last_traffic = now;
+        }
+    }
+
+    pub fn lost(&mut self, no_power: bool) {
+        self.lost = Some(if no_power {
+            Health::NoPower
+        } else {
+            Health::Disconnected
+        });
+    }
+
+    pub fn asleep(&mut self, now: Instant, asleep: bool) {
+        self.asleep = asleep;
+        self.last_traffic = now;
+    }
+
+    pub fn restored(&mut self, now: Instant) {
+        self.lost = None;
+        self.errors = 0;
+        self.last_traffic = now;
+    }
+
+    /// Reevaluate the health, returning it if it changed
+    pub fn update(&mut self, now: Instant) -> Option<Health> {
+        let idle = self
+            .idle_timeout
+            .is_some_and(|timeout| now.duration_since(self.last_traffic) >= timeout)
+            && !self.asleep;
+
+        let state = if let Some(lost) = self.lost {
+            lost
+        } else if self.errors >= STUCK_ERRORS {
+            Health::Stuck
+        } else if idle {
+            Health::Idle
+        } else {
+            Health::Healthy
+        };
+
+        (state != self.state).then(|| {
+            self.state = state;
+            state
+        })
+    }
+}
+
+/// Reader reporting changes of the bus health as events, and publishing the health
+pub struct Supervised<R: FrameReader> {
+    target: R,
+    monitor: HealthMonitor,
+    health_tx: watch::Sender<Option<Health>>,
+    events: VecDeque<BusEvent>,
+}
+
+impl<R: FrameReader> Supervised<R> {
+    pub fn new(
+        target: R,
+        idle_timeout: Option<Duration>,
+        health_tx: watch::Sender<Option<Health>>,
+    ) -> Self {
+        let monitor = HealthMonitor::new(Instant::now(), idle_timeout);
+        health_tx.send_replace(Some(monitor.state()));
+
+        Supervised {
+            target,
+            monitor,
+            health_tx,
+            events: VecDeque::new(),
+        }
+    }
+}
+
+impl<R: FrameReader> FrameReader for Supervised<R> {
+    fn name(&self) -> &str {
+        self.target.name()
+    }
+
+    fn try_read(&mut self) -> Option<Frame> {
+        let frame = self.target.try_read()?;
+        self.monitor.observe(Instant::now(), &frame);
+
+        Some(frame)
+    }
+
+    fn switch_schedule(&mut self, switch: &msg::SwitchSchedule) -> Result<()> {
+        self.target.switch_schedule(switch)
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        self.target.sleep()
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        self.target.wakeup()
+    }
+
+    fn try_read_event(&mut self) -> Option<BusEvent> {
+        while let Some(event) = self.target.try_read_event() {
+            match &event {
+                BusEvent::Lost { no_power, .. } => self.monitor.lost(*no_power),
+                BusEvent::Restored => self.monitor.restored(Instant::now()),
+                BusEvent::Asleep => self.monitor.asleep(Instant::now(), true),
+                BusEvent::Awake => self.monitor.asleep(Instant::now(), false),
+                BusEvent::Health(_) => {}
+            }
+
+            self.events.push_back(event);
+        }
+
+        // Polled every tick, so idleness is noticed without traffic
+        if let Some(health) = self.monitor.update(Instant::now()) {
+            log::info!("{} health is {health:?}", self.target.name());
+
+            self.health_tx.send_replace(Some(health));
+            self.events.push_back(BusEvent::Health(health));
+        }
+
+        self.events.pop_front()
+    }
+
+    fn close(&mut self) -> Option<JoinHandle<()>> {
+        self.target.close()
+    }
+}
+
+impl<R: Slave> Slave for Supervised<R> {
+    fn update(&mut self, f: &Frame) -> Result<()> {
+        self.target.update(f)
+    }
+}
+
+impl<R: Master> Master for Supervised<R> {
+    fn write(&mut self, frame: &Frame) -> Result<()> {
+        self.target.write(frame)
+    }
+
+    fn request_update(&mut self, id: u32) -> Result<()> {
+        self.target.request_update(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameInfo;
+
+    use pretty_assertions::assert_eq;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn frame(flags: FrameFlags) -> Frame {
+        Frame {
+            id: 0x10,
+            msg: vec![1, 2],
+            info: Some(FrameInfo {
+                flags,
+                ..FrameInfo::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_stuck_and_recovered() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(start, Some(IDLE_TIMEOUT));
+
+        for _ in 0..STUCK_ERRORS - 1 {
+            monitor.observe(start, &frame(FrameFlags::SYNCH_ERROR));
+        }
+        assert_eq!(monitor.update(start), None);
+
+        monitor.observe(start, &frame(FrameFlags::BIT_ERROR));
+        assert_eq!(monitor.update(start), Some(Health::Stuck));
+        assert_eq!(monitor.update(start), None);
+
+        monitor.observe(start, &frame(FrameFlags::RX));
+        assert_eq!(monitor.update(start), Some(Health::Healthy));
+    }
+
+    #[test]
+    fn test_idle_slave() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(start, Some(IDLE_TIMEOUT));
+
+        assert_eq!(monitor.update(start + IDLE_TIMEOUT / 2), None);
+        assert_eq!(monitor.update(start + IDLE_TIMEOUT), Some(Health::Idle));
+
+        // Errors holding the bus dominant are not traffic
+        let later = start + IDLE_TIMEOUT * 2;
+        monitor.observe(later, &frame(FrameFlags::SYNCH_ERROR));
+        assert_eq!(monitor.update(later), None);
+
+        monitor.observe(later, &frame(FrameFlags::NODATA));
+        assert_eq!(monitor.update(later), Some(Health::Healthy));
+
+        // A sleeping bus is silent on purpose
+        monitor.asleep(later, true);
+        assert_eq!(monitor.update(later + IDLE_TIMEOUT * 2), None);
+
+        let mut master = HealthMonitor::new(start, None);
+        assert_eq!(master.update(start + IDLE_TIMEOUT * 10), None);
+    }
+
+    #[test]
+    fn test_lost_and_restored() {
+        let start = Instant::now();
+        let mut monitor = HealthMonitor::new(start, Some(IDLE_TIMEOUT));
+
+        monitor.lost(true);
+        assert_eq!(monitor.update(start), Some(Health::NoPower));
+
+        // Nothing is read while lost, that is not idleness
+        monitor.lost(false);
+        assert_eq!(
+            monitor.update(start + IDLE_TIMEOUT * 2),
+            Some(Health::Disconnected)
+        );
+
+        let later = start + IDLE_TIMEOUT * 3;
+        monitor.restored(later);
+        assert_eq!(monitor.update(later), Some(Health::Healthy));
+    }
+}
@@ -0,0 +1,517 @@
+//! Kvaser LIN channel owned by a dedicated thread.
+//!
+//! All linlib calls for a channel run on its thread, so slow or hung driver calls never block the
+//! tokio runtime serving the control socket and the other buses. The worker talks to the thread
+//! through typed commands and responses only.
+//!
+//! The thread blocks in `linReadMessageWait` and hands frames to the worker as soon as they
+//! arrive, so nothing builds up in the driver queue.
+//!
+//! When the interface is lost, e.g. unplugged, the thread reports it and keeps reopening the
+//! channel with backoff until it is back, republishing the slave responses.
+use crate::frame::Frame;
+use crate::kvaser::{self, KvaserLinux};
+use crate::masterslave::{BusEvent, FrameReader, Master, Slave};
+use crate::msg::{FrameModel, HostMode};
+use crate::noechoslave::NoEchoSlave;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, oneshot};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+/// Longest a queued command waits for a blocking read to return
+const READ_WAIT: Duration = Duration::from_millis(1);
+
+/// Longest opening waits for the thread before leaving it behind
+const OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest closing waits for the thread before leaving it behind
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// First and longest wait between attempts to reopen a lost channel
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Request to the thread owning the channel
+#[derive(Debug)]
+enum Command {
+    Write(Frame),
+    RequestUpdate(u32),
+    Update(Frame),
+    Wakeup,
+    Close,
+}
+
+/// Answer from the thread owning the channel
+#[derive(Debug)]
+enum Response {
+    /// Frame read from the bus
+    Read(Frame),
+    /// Command that failed, successful commands are not answered
+    Failed(anyhow::Error),
+    /// The channel was lost, commands are dropped until it is restored
+    Lost {
+        reason: String,
+        no_power: bool,
+    },
+    Restored,
+    Closed,
+}
+
+pub struct KvaserChannel {
+    name: String,
+    host_mode: HostMode,
+    /// A go-to-sleep command was seen and nothing since
+    asleep: bool,
+    command_tx: mpsc::Sender<Command>,
+    response_rx: mpsc::Receiver<Response>,
+    /// Failed command not yet reported to the worker
+    failure: Option<anyhow::Error>,
+    events: VecDeque<BusEvent>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl KvaserChannel {
+    pub async fn new_slave(
+        name: &str,
+        device_id: &str,
+        baudrate: u32,
+        model: FrameModel,
+        notify: Arc<Notify>,
+    ) -> Result<impl Slave> {
+        Ok(NoEchoSlave::new(
+            KvaserChannel::new(name, device_id, HostMode::Slave, baudrate, model, notify).await?,
+        ))
+    }
+
+    pub async fn new_master(
+        name: &str,
+        device_id: &str,
+        baudrate: u32,
+        model: FrameModel,
+        notify: Arc<Notify>,
+    ) -> Result<impl Master> {
+        KvaserChannel::new(name, device_id, HostMode::Master, baudrate, model, notify).await
+    }
+
+    /// Open the channel on its thread, notify is signalled whenever responses are ready to be read
+    async fn new(
+        name: &str,
+        device_id: &str,
+        host_mode: HostMode,
+        baudrate: u32,
+        model: FrameModel,
+        notify: Arc<Notify>,
+    ) -> Result<KvaserChannel> {
+        let (command_tx, command_rx) = mpsc::channel();
+        let (response_tx, response_rx) = mpsc::channel();
+        let (open_tx, open_rx) = oneshot::channel();
+
+        let channel_thread = ChannelThread {
+            name: name.to_string(),
+            device_id: device_id.to_string(),
+            host_mode,
+            baudrate,
+            model,
+            command_rx,
+            response_tx,
+            notify,
+            published: HashMap::new(),
+        };
+
+        let thread = thread::Builder::new()
+            .name(format!("kvaser-{name}"))
+            .spawn(move || {
+                // The handle is only ever used from this thread
+                let kvaser = match channel_thread.open() {
+                    Ok(kvaser) => {
+                        let _ = open_tx.send(Ok(()));
+                        kvaser
+                    }
+                    Err(e) => {
+                        let _ = open_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                channel_thread.run(kvaser);
+            })?;
+
+        // A thread still opening when given up on closes the channel as soon as it is open, its
+        // commands are gone
+        time::timeout(OPEN_TIMEOUT, open_rx)
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out opening the Kvaser channel for {name}"))?
+            .map_err(|_| anyhow::anyhow!("Kvaser thread for {name} exited while opening"))??;
+
+        Ok(KvaserChannel {
+            name: name.to_string(),
+            host_mode,
+            asleep: false,
+            command_tx,
+            response_rx,
+            failure: None,
+            events: VecDeque::new(),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue a command, reporting a command that failed since the last call first
+    fn send(&mut self, command: Command) -> Result<()> {
+        if let Some(err) = self.failure.take() {
+            return Err(err);
+        }
+
+        self.command_tx
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Kvaser thread for {} has stopped", self.name))
+    }
+
+    /// Ask the thread to close the channel, the returned wait blocks until it is closed. None if
+    /// the channel is already closing.
+    fn start_close(&mut self) -> Option<impl FnOnce() + Send + 'static> {
+        if let Some(err) = self.failure.take() {
+            log::error!("{} {err:?}", self.name);
+        }
+
+        let thread = self.thread.take()?;
+
+        if self.command_tx.send(Command::Close).is_err() {
+            return None;
+        }
+
+        let name = self.name.clone();
+        let response_rx = std::mem::replace(&mut self.response_rx, mpsc::channel().1);
+
+        Some(move || wait_closed(&name, &response_rx, thread))
+    }
+
+    /// Follow the sleep state of the bus through the frames read
+    fn track_sleep(&mut self, frame: &Frame) {
+        if frame.is_go_to_sleep() {
+            if !self.asleep {
+                log::info!("{} bus went to sleep", self.name);
+
+                self.asleep = true;
+                self.events.push_back(BusEvent::Asleep);
+            }
+        } else if self.asleep
+            && (frame.is_wakeup() || frame.info.is_none_or(|info| !info.flags.is_error()))
+        {
+            // Any valid traffic wakes the bus, disturbances do not
+            log::info!("{} bus woke up", self.name);
+
+            self.asleep = false;
+            self.events.push_back(BusEvent::Awake);
+        }
+    }
+}
+
+/// Why serving an open channel stopped
+enum Stop {
+    Closed,
+    Lost(anyhow::Error),
+}
+
+/// State of the thread owning the channel
+struct ChannelThread {
+    name: String,
+    device_id: String,
+    host_mode: HostMode,
+    baudrate: u32,
+    model: FrameModel,
+    command_rx: mpsc::Receiver<Command>,
+    response_tx: mpsc::Sender<Response>,
+    notify: Arc<Notify>,
+    /// Latest response per frame id, republished when the channel is reopened
+    published: HashMap<u32, Frame>,
+}
+
+impl ChannelThread {
+    fn open(&self) -> Result<KvaserLinux> {
+        KvaserLinux::new(
+            &self.name,
+            &self.device_id,
+            self.host_mode,
+            self.baudrate,
+            &self.model,
+        )
+    }
+
+    fn respond(&self, response: Response) -> bool {
+        let sent = self.response_tx.send(response).is_ok();
+        self.notify.notify_one();
+        sent
+    }
+
+    fn run(mut self, mut kvaser: KvaserLinux) {
+        loop {
+            match self.serve(&mut kvaser) {
+                Stop::Closed => {
+                    // Responses are not left behind in the interface
+                    for id in self.published.keys() {
+                        if let Err(err) = kvaser.clear(*id) {
+                            log::warn!("{} {err}", self.name);
+                        }
+                    }
+
+                    // The channel is closed before the close is answered
+                    drop(kvaser);
+                    self.respond(Response::Closed);
+                    return;
+                }
+
+                Stop::Lost(err) => {
+                    drop(kvaser);
+
+                    log::warn!("{} lost - {err}", self.name);
+                    let no_power = kvaser::is_no_power(&err);
+                    if !self.respond(Response::Lost {
+                        reason: err.to_string(),
+                        no_power,
+                    }) {
+                        return;
+                    }
+
+                    let Some(reopened) = self.reconnect(no_power) else {
+                        return;
+                    };
+                    kvaser = reopened;
+
+                    log::info!("{} restored", self.name);
+                    if !self.respond(Response::Restored) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Execute commands and read frames until the channel is closed or lost
+    fn serve(&mut self, kvaser: &mut KvaserLinux) -> Stop {
+        loop {
+            loop {
+                let command = match self.command_rx.try_recv() {
+                    Ok(command) => command,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return Stop::Closed,
+                };
+
+                let res = match &command {
+                    Command::Write(frame) => kvaser.write(frame),
+                    Command::RequestUpdate(id) => kvaser.request_update(*id),
+                    Command::Update(frame) => kvaser.update(frame).map(|()| {
+                        self.published.insert(frame.id, frame.clone());
+                    }),
+                    Command::Wakeup => kvaser.wakeup(),
+                    Command::Close => return Stop::Closed,
+                };
+
+                if let Err(err) = res {
+                    // A command failing because the channel is gone is part of the outage
+                    match kvaser.read_wait(Duration::ZERO) {
+                        Err(read_err) => return Stop::Lost(read_err),
+                        Ok(Some(frame)) => {
+                            if !self.respond(Response::Read(frame)) {
+                                return Stop::Closed;
+                            }
+                        }
+                        Ok(None) => {}
+                    }
+
+                    let err = err.context(format!("{command:?} failed"));
+                    if !self.respond(Response::Failed(err)) {
+                        return Stop::Closed;
+                    }
+                }
+            }
+
+            // Reads only fail once the channel is unusable, nothing to read is not an error
+            match kvaser.read_wait(READ_WAIT) {
+                Ok(Some(frame)) => {
+                    if !self.respond(Response::Read(frame)) {
+                        return Stop::Closed;
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => return Stop::Lost(err),
+            }
+        }
+    }
+
+    /// Reopen the channel with backoff, None if closed meanwhile
+    fn reconnect(&mut self, mut no_power: bool) -> Option<KvaserLinux> {
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+
+        loop {
+            // Waiting on commands keeps closing responsive during the outage
+            let deadline = Instant::now() + backoff;
+            loop {
+                match self
+                    .command_rx
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    Ok(Command::Update(frame)) => {
+                        self.published.insert(frame.id, frame);
+                    }
+                    Ok(Command::Close) => {
+                        self.respond(Response::Closed);
+                        return None;
+                    }
+                    Ok(command) => log::debug!("{} dropped {command:?} while lost", self.name),
+                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                }
+            }
+
+            // Channel indices change when interfaces are plugged in again
+            if let Err(err) = kvaser::refresh_devices() {
+                log::debug!("{} failed to enumerate devices - {err}", self.name);
+            }
+
+            match self.open() {
+                Ok(mut kvaser) => {
+                    for frame in self.published.values() {
+                        if let Err(err) = kvaser.update(frame) {
+                            log::error!("{} failed to republish - {err}", self.name);
+                        }
+                    }
+
+                    return Some(kvaser);
+                }
+
+                Err(err) => {
+                    log::debug!("{} failed to reopen - {err}", self.name);
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+
+                    // The interface may be back while the bus is still unpowered, or vice versa
+                    if kvaser::is_no_power(&err) != no_power {
+                        no_power = !no_power;
+                        if !self.respond(Response::Lost {
+                            reason: err.to_string(),
+                            no_power,
+                        }) {
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FrameReader for KvaserChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn try_read(&mut self) -> Option<Frame> {
+        loop {
+            match self.response_rx.try_recv().ok()? {
+                Response::Read(frame) => {
+                    self.track_sleep(&frame);
+                    return Some(frame);
+                }
+                Response::Failed(err) => {
+                    if let Some(previous) = self.failure.replace(err) {
+                        log::error!("{} {previous:?}", self.name);
+                    }
+                }
+                Response::Lost { reason, no_power } => {
+                    self.events.push_back(BusEvent::Lost { reason, no_power })
+                }
+                Response::Restored => self.events.push_back(BusEvent::Restored),
+                Response::Closed => {}
+            }
+        }
+    }
+
+    fn try_read_event(&mut self) -> Option<BusEvent> {
+        self.events.pop_front()
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        match self.host_mode {
+            HostMode::Master => self.send(Command::Write(Frame::go_to_sleep())),
+            HostMode::Slave => Err(anyhow::anyhow!(
+                "{} is a slave, only the master can put the bus to sleep",
+                self.name
+            )),
+        }
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        self.send(Command::Wakeup)
+    }
+
+    fn close(&mut self) -> Option<JoinHandle<()>> {
+        // Waiting for the thread blocks
+        self.start_close().map(task::spawn_blocking)
+    }
+}
+
+impl Slave for KvaserChannel {
+    fn update(&mut self, f: &Frame) -> Result<()> {
+        // A slave may wake up the sleeping bus
+        if f.is_wakeup() {
+            return self.send(Command::Wakeup);
+        }
+
+        self.send(Command::Update(f.clone()))
+    }
+}
+
+impl Master for KvaserChannel {
+    fn write(&mut self, frame: &Frame) -> Result<()> {
+        if frame.is_wakeup() {
+            return self.send(Command::Wakeup);
+        }
+
+        self.send(Command::Write(frame.clone()))
+    }
+
+    fn request_update(&mut self, id: u32) -> Result<()> {
+        self.send(Command::RequestUpdate(id))
+    }
+}
+
+/// A channel dropped without being closed is closed in the background, its device may still be
+/// in use for a while
+impl Drop for KvaserChannel {
+    fn drop(&mut self) {
+        let Some(wait) = self.start_close() else {
+            return;
+        };
+
+        // Waiting for the thread blocks, it is left to a blocking task when dropped on the runtime
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(wait);
+            }
+            Err(_) => wait(),
+        }
+    }
+}
+
+/// Wait for the thread to close the channel, a hung driver call leaves it behind after a while
+fn wait_closed(name: &str, response_rx: &mpsc::Receiver<Response>, thread: thread::JoinHandle<()>) {
+    let closed = loop {
+        match response_rx.recv_timeout(CLOSE_TIMEOUT) {
+            Ok(Response::Closed) => break Ok(()),
+            Ok(_) => {}
+            Err(err) => break Err(err),
+        }
+    };
+
+    match closed.context(format!("Kvaser thread for {name} did not close")) {
+        Ok(()) => {
+            let _ = thread.join();
+        }
+        Err(err) => log::warn!("{err:?}"),
+    }
+}
@@ -0,0 +1,217 @@
+//! Kvaser channels and the selectors addressing them in bus configurations.
+use anyhow::Result;
+use serde::Serialize;
+
+/// Kvaser channel found when the library was initialized
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Device {
+    /// Device id used to open the channel, e.g. "011121:1"
+    pub device_id: String,
+    /// canlib channel index
+    pub channel: i32,
+    /// Channel number on the card, starting at 1
+    pub card_channel: u32,
+    /// canlib hardware type, one of the `canHWTYPE_` values
+    pub card_type: u32,
+    pub ean: String,
+    pub serial: u64,
+    pub firmware: String,
+    pub lin: bool,
+    pub can: bool,
+    /// Host device of the running bus using the channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_by: Option<String>,
+}
+
+/// Way of addressing a channel in `device_id`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    /// Device id as listed, e.g. "011121:1"
+    DeviceId(String),
+    /// "ean:73-30130-01121-7", optionally with "/ch:1"
+    Ean { ean: String, channel: Option<u32> },
+    /// "serial:11121", optionally with "/ch:1"
+    Serial { serial: u64, channel: Option<u32> },
+    /// "index:3", the canlib channel index
+    Index(i32),
+    /// "any-lin", the first LIN capable channel not in use
+    AnyLin,
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector> {
+        let invalid = || anyhow::anyhow!("Invalid device selector {selector}");
+
+        if selector == "any-lin" {
+            return Ok(Selector::AnyLin);
+        }
+
+        let Some((kind, value)) = selector.split_once(':') else {
+            return Ok(Selector::DeviceId(selector.to_string()));
+        };
+
+        let (value, channel) = match value.split_once("/ch:") {
+            Some((value, channel)) => (value, Some(channel.parse().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+
+        match (kind, channel) {
+            ("ean", _) => Ok(Selector::Ean {
+                ean: normalize_ean(value),
+                channel,
+            }),
+            ("serial", _) => Ok(Selector::Serial {
+                serial: value.parse().map_err(|_| invalid())?,
+                channel,
+            }),
+            ("index", None) => Ok(Selector::Index(value.parse().map_err(|_| invalid())?)),
+            ("index", Some(_)) => Err(invalid()),
+            // Device ids contain a colon too
+            _ => Ok(Selector::DeviceId(selector.to_string())),
+        }
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        let on_channel =
+            |channel: &Option<u32>| channel.is_none_or(|channel| channel == device.card_channel);
+
+        match self {
+            Selector::DeviceId(device_id) => device.device_id == *device_id,
+            Selector::Ean { ean, channel } => {
+                normalize_ean(&device.ean) == *ean && on_channel(channel)
+            }
+            Selector::Serial { serial, channel } => device.serial == *serial && on_channel(channel),
+            Selector::Index(index) => device.channel == *index,
+            Selector::AnyLin => device.lin,
+        }
+    }
+}
+
+fn normalize_ean(ean: &str) -> String {
+    ean.chars().filter(|c| *c != '-').collect()
+}
+
+/// The device selected, the first free one for `any-lin`, an error if none or several match
+pub fn select<'a>(
+    selector: &str,
+    devices: &'a [Device],
+    is_free: impl Fn(&Device) -> bool,
+) -> Result<&'a Device> {
+    let parsed = Selector::parse(selector)?;
+
+    let mut matching = devices.iter().filter(|device| parsed.matches(device));
+
+    if parsed == Selector::AnyLin {
+        return matching
+            .find(|device| is_free(device))
+            .ok_or_else(|| anyhow::anyhow!("No free LIN capable Kvaser channel found"));
+    }
+
+    let matching: Vec<&Device> = matching.collect();
+
+    match matching.as_slice() {
+        [device] => Ok(device),
+
+        [] => Err(anyhow::anyhow!(
+            "Kvaser device with device_id {selector} not found, found {}",
+            device_ids(devices.iter())
+        )),
+
+        _ => Err(anyhow::anyhow!(
+            "Kvaser device selector {selector} is ambiguous, it matches {}",
+            device_ids(matching.into_iter())
+        )),
+    }
+}
+
+fn device_ids<'a>(devices: impl Iterator<Item = &'a Device>) -> String {
+    devices
+        .map(|device| device.device_id.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn device(channel: i32, serial: u64, card_channel: u32, lin: bool) -> Device {
+        Device {
+            device_id: format!("0{serial}:{card_channel}"),
+            channel,
+            card_channel,
+            card_type: 0,
+            ean: "73-30130-01121-7".to_string(),
+            serial,
+            firmware: "3.25.423".to_string(),
+            lin,
+            can: true,
+            used_by: None,
+        }
+    }
+
+    fn devices() -> Vec<Device> {
+        vec![
+            device(0, 11121, 1, false),
+            device(1, 11121, 2, true),
+            device(2, 22222, 1, true),
+        ]
+    }
+
+    fn selected(selector: &str) -> Result<i32> {
+        select(selector, &devices(), |device| device.channel != 1).map(|device| device.channel)
+    }
+
+    #[test]
+    fn test_parse_selectors() {
+        assert_eq!(
+            Selector::parse("011121:1").unwrap(),
+            Selector::DeviceId("011121:1".to_string())
+        );
+        assert_eq!(
+            Selector::parse("ean:73-30130-01121-7").unwrap(),
+            Selector::Ean {
+                ean: "7330130011217".to_string(),
+                channel: None
+            }
+        );
+        assert_eq!(
+            Selector::parse("serial:11121/ch:2").unwrap(),
+            Selector::Serial {
+                serial: 11121,
+                channel: Some(2)
+            }
+        );
+        assert_eq!(Selector::parse("index:3").unwrap(), Selector::Index(3));
+        assert_eq!(Selector::parse("any-lin").unwrap(), Selector::AnyLin);
+
+        assert!(Selector::parse("serial:abc").is_err());
+        assert!(Selector::parse("index:1/ch:1").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(selected("011121:2").unwrap(), 1);
+        assert_eq!(selected("serial:11121/ch:2").unwrap(), 1);
+        assert_eq!(selected("ean:7330130011217/ch:2").unwrap(), 1);
+        assert_eq!(selected("index:2").unwrap(), 2);
+
+        // Channel 1 is the first LIN channel but in use
+        assert_eq!(selected("any-lin").unwrap(), 2);
+
+        assert!(
+            selected("011121:9")
+                .unwrap_err()
+                .to_string()
+                .contains("not found")
+        );
+        assert!(
+            selected("serial:11121")
+                .unwrap_err()
+                .to_string()
+                .contains("ambiguous, it matches 011121:1, 011121:2")
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Safe wrapper over the Kvaser linlib and canlib bindings.
+//!
+//! Failed calls become a `LinError` carrying the driver's own description of the status, channel
+//! handles are closed when dropped and message flags are typed.
+#![allow(non_upper_case_globals)]
+
+use crate::frame::{Frame, FrameFlags, FrameInfo};
+use crate::kvaser_raw_binding::*;
+use crate::msg::{Checksum, HostMode};
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::mem::{size_of_val, zeroed};
+use std::os::raw::{c_char, c_uint, c_void};
+use std::time::Duration;
+
+/// Longest LIN frame data, with room to spare for the driver
+const MAX_DATA_LENGTH: usize = 16;
+
+const ERROR_TEXT_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum LinError {
+    #[error("{call} failed: {text} (linlib status {status})")]
+    Lin {
+        call: &'static str,
+        status: LinStatus,
+        text: String,
+    },
+
+    #[error("{call} failed: {text} (canlib status {status})")]
+    Can {
+        call: &'static str,
+        status: canStatus,
+        text: String,
+    },
+}
+
+impl LinError {
+    fn lin(call: &'static str, status: LinStatus) -> Self {
+        LinError::Lin {
+            call,
+            status,
+            text: error_text(|buf, len| unsafe { linGetErrorText(status, buf, len) }),
+        }
+    }
+
+    fn can(call: &'static str, status: canStatus) -> Self {
+        LinError::Can {
+            call,
+            status,
+            text: error_text(|buf, len| unsafe { canGetErrorText(status, buf, len) }),
+        }
+    }
+
+    /// The channel or device does not exist, e.g. the LIN bus has no power
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            LinError::Lin { status, .. } => *status == LinStatus_linERR_NOTFOUND,
+            LinError::Can { status, .. } => *status == canStatus_canERR_NOTFOUND,
+        }
+    }
+
+    /// The LIN transceiver has no supply, opening a channel on an unpowered bus finds no bus
+    pub fn is_no_power(&self) -> bool {
+        match self {
+            LinError::Lin { call, status, .. } => {
+                *status == LinStatus_linERR_NO_REF_POWER
+                    || (*call == "linOpenChannel" && *status == LinStatus_linERR_NOTFOUND)
+            }
+            LinError::Can { .. } => false,
+        }
+    }
+}
+
+fn check_lin(call: &'static str, status: LinStatus) -> Result<(), LinError> {
+    match status {
+        LinStatus_linOK => Ok(()),
+        _ => Err(LinError::lin(call, status)),
+    }
+}
+
+fn check_can(call: &'static str, status: canStatus) -> Result<(), LinError> {
+    match status {
+        canStatus_canOK => Ok(()),
+        _ => Err(LinError::can(call, status)),
+    }
+}
+
+fn error_text(get_text: impl FnOnce(*mut c_char, c_uint) -> i32) -> String {
+    let mut buf: [c_char; ERROR_TEXT_LENGTH] = [0; ERROR_TEXT_LENGTH];
+
+    if get_text(buf.as_mut_ptr(), (ERROR_TEXT_LENGTH - 1) as c_uint) != 0 {
+        return "unknown error".to_string();
+    }
+
+    // The last byte is never written, so the text is always terminated
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Setup flags for `Channel::setup`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupFlags(u32);
+
+impl SetupFlags {
+    pub const ENHANCED_CHECKSUM: SetupFlags = SetupFlags(LIN_ENHANCED_CHECKSUM);
+    pub const VARIABLE_DLC: SetupFlags = SetupFlags(LIN_VARIABLE_DLC);
+
+    pub fn empty() -> SetupFlags {
+        SetupFlags(0)
+    }
+}
+
+impl std::ops::BitOr for SetupFlags {
+    type Output = SetupFlags;
+
+    fn bitor(self, rhs: SetupFlags) -> SetupFlags {
+        SetupFlags(self.0 | rhs.0)
+    }
+}
+
+/// Flags of a single frame id for `Channel::setup_message`
+///
+/// linlib.h documents `LIN_MSG_USE_STANDARD_PARITY` as "Use standard (1.x) parity for the
+/// specified msg" and `LIN_MSG_USE_ENHANCED_PARITY` as "Use enhanced (2.x) parity for the specified
+/// msg", linlib calls the checksum parity. `linSetupIllegalMessage` is the only call taking flags
+/// for a single id, only these flags are passed to it and never `LIN_MSG_DISTURB_CSUM` or
+/// `LIN_MSG_DISTURB_PARITY`, so the frame itself is sent intact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageFlags(u32);
+
+impl MessageFlags {
+    /// Classic checksum, linlib calls it standard parity
+    pub const CLASSIC_CHECKSUM: MessageFlags = MessageFlags(LIN_MSG_USE_STANDARD_PARITY);
+    /// Enhanced checksum, linlib calls it enhanced parity
+    pub const ENHANCED_CHECKSUM: MessageFlags = MessageFlags(LIN_MSG_USE_ENHANCED_PARITY);
+}
+
+impl From<Checksum> for MessageFlags {
+    fn from(checksum: Checksum) -> MessageFlags {
+        match checksum {
+            Checksum::Classic => MessageFlags::CLASSIC_CHECKSUM,
+            Checksum::Enhanced => MessageFlags::ENHANCED_CHECKSUM,
+        }
+    }
+}
+
+/// Message flags reported by the driver
+pub fn message_flags(flags: u32) -> FrameFlags {
+    [
+        (LIN_TX, FrameFlags::TX),
+        (LIN_RX, FrameFlags::RX),
+        (LIN_WAKEUP_FRAME, FrameFlags::WAKEUP_FRAME),
+        (LIN_NODATA, FrameFlags::NODATA),
+        (LIN_CSUM_ERROR, FrameFlags::CSUM_ERROR),
+        (LIN_PARITY_ERROR, FrameFlags::PARITY_ERROR),
+        (LIN_SYNCH_ERROR, FrameFlags::SYNCH_ERROR),
+        (LIN_BIT_ERROR, FrameFlags::BIT_ERROR),
+    ]
+    .into_iter()
+    .filter(|(lin_flag, _)| flags & lin_flag != 0)
+    .fold(FrameFlags::empty(), |acc, (_, flag)| acc | flag)
+}
+
+/// Initialize linlib, must be done once before any other call
+pub fn initialize_library() {
+    unsafe { linInitializeLibrary() };
+}
+
+/// Rescan for plugged and unplugged interfaces, channel indices may change
+pub fn enumerate_hardware() -> Result<(), LinError> {
+    check_can("canEnumHardware", unsafe { canEnumHardware() })
+}
+
+pub fn channel_count() -> Result<i32, LinError> {
+    let mut count: i32 = 0;
+    check_can("canGetNumberOfChannels", unsafe {
+        canGetNumberOfChannels(&mut count)
+    })?;
+
+    Ok(count)
+}
+
+fn channel_data<T: Default>(channel: i32, item: u32) -> Result<T, LinError> {
+    let mut data = T::default();
+    check_can("canGetChannelData", unsafe {
+        canGetChannelData(
+            channel,
+            item as i32,
+            &mut data as *mut T as *mut c_void,
+            size_of_val(&data),
+        )
+    })?;
+
+    Ok(data)
+}
+
+/// Serial number of the card the channel is on, as the low and high word
+pub fn card_serial(channel: i32) -> Result<[u32; 2], LinError> {
+    channel_data(channel, canCHANNELDATA_CARD_SERIAL_NO)
+}
+
+/// Hardware type of the card the channel is on, one of the canlib `canHWTYPE_` values
+pub fn card_type(channel: i32) -> Result<u32, LinError> {
+    channel_data(channel, canCHANNELDATA_CARD_TYPE)
+}
+
+/// EAN of the card the channel is on, e.g. "73-30130-01121-7"
+pub fn card_ean(channel: i32) -> Result<String, LinError> {
+    let [low, high]: [u32; 2] = channel_data(channel, canCHANNELDATA_CARD_UPC_NO)?;
+
+    Ok(format_ean(low, high))
+}
+
+/// The EAN is stored as binary coded decimal digits
+fn format_ean(low: u32, high: u32) -> String {
+    let digits = format!("{high:x}{low:08x}");
+    let part = |from: usize, to: usize| digits.get(from..to.min(digits.len())).unwrap_or("");
+
+    format!(
+        "{}-{}-{}-{}",
+        part(0, 2),
+        part(2, 7),
+        part(7, 12),
+        part(12, digits.len())
+    )
+}
+
+/// Firmware version of the card the channel is on, e.g. "3.25.423"
+pub fn card_firmware(channel: i32) -> Result<String, LinError> {
+    let [build, _, minor, major]: [u16; 4] =
+        channel_data(channel, canCHANNELDATA_CARD_FIRMWARE_REV)?;
+
+    Ok(format!("{major}.{minor}.{build}"))
+}
+
+pub fn capabilities(channel: i32) -> Result<Capabilities, LinError> {
+    channel_data(channel, canCHANNELDATA_CHANNEL_CAP).map(Capabilities)
+}
+
+/// Whether the channel is open, by this or any other process
+pub fn is_open(channel: i32) -> Result<bool, LinError> {
+    let flags: u32 = channel_data(channel, canCHANNELDATA_CHANNEL_FLAGS)?;
+
+    Ok(flags & canCHANNEL_IS_OPEN != 0)
+}
+
+/// Channel capabilities reported by canlib
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub fn can(self) -> bool {
+        self.0 & canCHANNEL_CAP_EXTENDED_CAN != 0
+    }
+
+    pub fn can_fd(self) -> bool {
+        self.0 & canCHANNEL_CAP_CAN_FD != 0
+    }
+
+    pub fn lin(self) -> bool {
+        self.0 & canCHANNEL_CAP_LIN_HYBRID != 0
+    }
+}
+
+/// Open LIN channel, closed when dropped
+///
+/// A linlib handle must not be shared between threads.
+pub struct Channel {
+    handle: LinHandle,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl Channel {
+    pub fn open(channel: i32, host_mode: HostMode) -> Result<Channel, LinError> {
+        let flags = match host_mode {
+            HostMode::Master => LIN_MASTER,
+            HostMode::Slave => LIN_SLAVE,
+        };
+
+        // The handle doubles as the status when negative
+        let handle = unsafe { linOpenChannel(channel, flags as i32) };
+        if handle < 0 {
+            return Err(LinError::lin("linOpenChannel", handle));
+        }
+
+        Ok(Channel {
+            handle,
+            _not_sync: PhantomData,
+        })
+    }
+
+    pub fn bus_on(&mut self) -> Result<(), LinError> {
+        check_lin("linBusOn", unsafe { linBusOn(self.handle) })
+    }
+
+    pub fn bus_off(&mut self) -> Result<(), LinError> {
+        check_lin("linBusOff", unsafe { linBusOff(self.handle) })
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<(), LinError> {
+        check_lin("linSetBitrate", unsafe {
+            linSetBitrate(self.handle, bitrate)
+        })
+    }
+
+    pub fn setup(&mut self, flags: SetupFlags, bitrate: u32) -> Result<(), LinError> {
+        check_lin("linSetupLIN", unsafe {
+            linSetupLIN(self.handle, flags.0, bitrate)
+        })
+    }
+
+    /// Stop answering the header of a frame, slave only
+    pub fn clear_message(&mut self, id: u32) -> Result<(), LinError> {
+        check_lin("linClearMessage", unsafe {
+            linClearMessage(self.handle, id)
+        })
+    }
+
+    /// Send count wake-up frames, interval apart
+    pub fn write_wakeup(&mut self, count: u32, interval: Duration) -> Result<(), LinError> {
+        check_lin("linWriteWakeup", unsafe {
+            linWriteWakeup(self.handle, count, interval.as_millis() as _)
+        })
+    }
+
+    /// Set up how a single frame id is sent, overriding the setup of the channel
+    pub fn setup_message(&mut self, id: u32, flags: MessageFlags) -> Result<(), LinError> {
+        // No disturbance flags and no delay between header and response, see `MessageFlags`
+        check_lin("linSetupIllegalMessage", unsafe {
+            linSetupIllegalMessage(self.handle, id, flags.0, 0)
+        })
+    }
+
+    /// Read the next message, waiting up to timeout if given, None if nothing arrived
+    pub fn read(&mut self, timeout: Option<Duration>) -> Result<Option<Frame>, LinError> {
+        let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+
+        let mut id: u32 = 0;
+        let mut length: u32 = 0;
+        let mut flags: u32 = 0;
+
+        let mut info: LinMessageInfo = unsafe { zeroed() };
+
+        let (call, status) = unsafe {
+            match timeout {
+                None => (
+                    "linReadMessage",
+                    linReadMessage(
+                        self.handle,
+                        &mut id,
+                        data.as_mut_ptr() as *mut c_void,
+                        &mut length,
+                        &mut flags,
+                        &mut info,
+                    ),
+                ),
+                Some(timeout) => (
+                    "linReadMessageWait",
+                    linReadMessageWait(
+                        self.handle,
+                        &mut id,
+                        data.as_mut_ptr() as *mut c_void,
+                        &mut length,
+                        &mut flags,
+                        &mut info,
+                        timeout.as_millis() as _,
+                    ),
+                ),
+            }
+        };
+
+        match status {
+            LinStatus_linERR_NOMSG | LinStatus_linERR_TIMEOUT => return Ok(None),
+            _ => check_lin(call, status)?,
+        }
+
+        let mut msg = data.to_vec();
+        msg.truncate(length as usize);
+
+        Ok(Some(Frame {
+            id,
+            msg,
+            info: Some(FrameInfo {
+                timestamp: info.timestamp,
+                pid: info.idPar,
+                checksum: info.checkSum,
+                bitrate: info.bitrate as u32,
+                synch_break_length: info.synchBreakLength,
+                frame_length: info.frameLength,
+                flags: message_flags(flags),
+            }),
+        }))
+    }
+
+    /// Write a complete frame, master only
+    pub fn write(&mut self, id: u32, data: &[u8]) -> Result<(), LinError> {
+        check_lin("linWriteMessage", unsafe {
+            linWriteMessage(
+                self.handle,
+                id,
+                data.as_ptr() as *const c_void,
+                data.len() as u32,
+            )
+        })
+    }
+
+    /// Send the header of a frame, master only
+    pub fn request(&mut self, id: u32) -> Result<(), LinError> {
+        check_lin("linRequestMessage", unsafe {
+            linRequestMessage(self.handle, id)
+        })
+    }
+
+    /// Set the response to send when the header of a frame is seen
+    pub fn update(&mut self, id: u32, data: &[u8]) -> Result<(), LinError> {
+        check_lin("linUpdateMessage", unsafe {
+            linUpdateMessage(
+                self.handle,
+                id,
+                data.as_ptr() as *const c_void,
+                data.len() as u32,
+            )
+        })
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        unsafe { linClose(self.handle) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_message_flags() {
+        assert_eq!(message_flags(0), FrameFlags::empty());
+        assert_eq!(
+            message_flags(LIN_RX | LIN_CSUM_ERROR | LIN_BIT_ERROR),
+            FrameFlags::RX | FrameFlags::CSUM_ERROR | FrameFlags::BIT_ERROR
+        );
+    }
+
+    #[test]
+    fn test_checksum_message_flags() {
+        assert_eq!(
+            MessageFlags::from(Checksum::Classic).0,
+            LIN_MSG_USE_STANDARD_PARITY
+        );
+        assert_eq!(
+            MessageFlags::from(Checksum::Enhanced).0,
+            LIN_MSG_USE_ENHANCED_PARITY
+        );
+
+        // The checksum is chosen, never disturbed
+        for checksum in [Checksum::Classic, Checksum::Enhanced] {
+            let flags = MessageFlags::from(checksum).0;
+            assert_eq!(flags & (LIN_MSG_DISTURB_CSUM | LIN_MSG_DISTURB_PARITY), 0);
+        }
+    }
+
+    #[test]
+    fn test_format_ean() {
+        assert_eq!(format_ean(0x3001_1217, 0x7_3301), "73-30130-01121-7");
+    }
+}
@@ -1,132 +1,264 @@
-#![allow(non_upper_case_globals)]
-
-use crate::frame::Frame;
-use crate::kvaser_raw_binding::*;
+use crate::frame::{self, Frame, FrameFlags};
+use crate::kvaser_device::{self, Device};
+use crate::kvaser_lib::{self, Channel, LinError, MessageFlags, SetupFlags};
 use crate::masterslave::{FrameReader, Master, Slave};
-use crate::msg::HostMode;
-use crate::noechoslave::NoEchoSlave;
+use crate::msg::{Checksum, Dlc, FrameModel, HostMode};
+use crate::scan::Probe;
+use crate::timing;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
-use std::cell::Cell;
-use std::collections::HashMap;
-use std::marker::PhantomData;
-use std::mem::size_of_val;
-use std::mem::zeroed;
-use std::os::raw::c_void;
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 pub struct KvaserLinux {
     name: String,
-    handle: LinHandle,
-    _not_send: PhantomData<Cell<()>>,
+    channel: Channel,
+    model: FrameModel,
+}
+
+/// Longest LIN response
+const MAX_LENGTH: usize = 8;
+
+/// Highest LIN frame id
+const MAX_ID: u32 = 0x3f;
+
+/// Baudrate a channel listens at while detecting the baudrate, the interface measures every
+/// synch field whatever it is configured to
+const DETECTION_BAUDRATE: u32 = 19_200;
+
+/// Synch fields measured to detect the baudrate, and the longest wait for them
+const DETECTION_SAMPLES: usize = 5;
+const DETECTION_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Wake-up frames sent per wake-up, the node repeats it if the bus stays silent
+const WAKEUP_COUNT: u32 = 1;
+const WAKEUP_INTERVAL: Duration = Duration::ZERO;
+
+static KVASER_INIT: OnceCell<()> = OnceCell::new();
+
+/// Channels found by the last enumeration
+static DEVICES: Mutex<Option<Vec<Device>>> = Mutex::new(None);
+
+/// Every Kvaser channel currently plugged in
+pub fn list_devices() -> Result<Vec<Device>> {
+    refresh_devices()
+}
+
+/// Device addressed by a selector such as "011121:1", "serial:11121/ch:1" or "any-lin"
+///
+/// Channels of the device ids in use, or open in any process, are not free for "any-lin".
+pub fn find_device(selector: &str, in_use: &[String]) -> Result<Device> {
+    if !has_mhydra_device()? {
+        return Err(anyhow::anyhow!(
+            "No mhydra devices found in /dev. Is the mhydra driver installed and hw connected?"
+        ));
+    }
+
+    let select = |devices: &[Device]| {
+        kvaser_device::select(selector, devices, |device| {
+            !in_use.contains(&device.device_id)
+                && !kvaser_lib::is_open(device.channel).unwrap_or(true)
+        })
+        .cloned()
+    };
+
+    // The device may have been plugged in since the last enumeration
+    select(&devices()?).or_else(|_| select(&refresh_devices()?))
+}
+
+fn devices() -> Result<Vec<Device>> {
+    let cached = DEVICES.lock().unwrap().clone();
+
+    match cached {
+        Some(devices) => Ok(devices),
+        None => refresh_devices(),
+    }
 }
 
-static KVASER_INIT: OnceCell<Result<HashMap<String, i32>>> = OnceCell::new();
+/// Enumerate the channels again, the library is initialized on first use
+pub fn refresh_devices() -> Result<Vec<Device>> {
+    // Held while enumerating, so buses reconnecting at once do not enumerate concurrently
+    let mut cached = DEVICES.lock().unwrap();
+
+    KVASER_INIT.get_or_init(|| {
+        log::info!("linInitializeLibrary");
+        kvaser_lib::initialize_library();
+    });
+
+    kvaser_lib::enumerate_hardware()?;
+    let devices = find_devices()?;
+
+    log::info!("Found devices {devices:?}");
+
+    *cached = Some(devices.clone());
+
+    Ok(devices)
+}
+
+/// Listen as slave and lock onto the baudrate of the synch fields sent by the master
+pub fn detect_baudrate(name: &str, device_id: &str, model: &FrameModel) -> Result<u32> {
+    let mut kvaser = KvaserLinux::new(name, device_id, HostMode::Slave, DETECTION_BAUDRATE, model)?;
+
+    let deadline = Instant::now() + DETECTION_TIMEOUT;
+    let mut measured = Vec::new();
+
+    while measured.len() < DETECTION_SAMPLES {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+
+        let Some(frame) = kvaser.read_wait(remaining)? else {
+            continue;
+        };
+
+        if let Some(info) = frame.info
+            && info.bitrate > 0
+            && !info.flags.contains(FrameFlags::SYNCH_ERROR)
+        {
+            measured.push(info.bitrate);
+        }
+    }
+
+    log::info!("{name} measured baudrates {measured:?}");
+
+    let baudrate = timing::lock_baudrate(&measured).ok_or_else(|| {
+        anyhow::anyhow!(
+            "No LIN baudrate detected on {name}, measured {measured:?} - is the master sending headers?"
+        )
+    })?;
+
+    log::info!("{name} detected baudrate {baudrate}");
+
+    Ok(baudrate)
+}
 
 impl KvaserLinux {
-    pub fn new_slave(name: &str, device_id: &str, baudrate: u32) -> Result<impl Slave> {
-        Ok(NoEchoSlave::new(KvaserLinux::new(
+    pub fn new_scanner(name: &str, device_id: &str, baudrate: u32) -> Result<impl Probe> {
+        KvaserLinux::new(
             name,
             device_id,
-            HostMode::Slave,
+            HostMode::Master,
             baudrate,
-        )?))
+            &FrameModel::default(),
+        )
     }
 
-    pub fn new_master(name: &str, device_id: &str, baudrate: u32) -> Result<impl Master> {
-        KvaserLinux::new(name, device_id, HostMode::Master, baudrate)
-    }
+    /// Open a channel, it is used by buses through `KvaserChannel` owning it on its own thread
+    pub(crate) fn new(
+        name: &str,
+        device_id: &str,
+        host_mode: HostMode,
+        baudrate: u32,
+        model: &FrameModel,
+    ) -> Result<KvaserLinux> {
+        validate(model)?;
 
-    fn new(name: &str, device_id: &str, host_mode: HostMode, baudrate: u32) -> Result<KvaserLinux> {
-        if !has_mhydra_device()? {
-            return Err(anyhow::anyhow!(
-                "No mhydra devices found in /dev. Is the mhydra driver installed and hw connected?"
-            ));
-        }
+        let channel_id = find_device(device_id, &[])?.channel;
 
-        let id_map = KVASER_INIT
-            .get_or_init(|| {
-                log::info!("linInitializeLibrary");
-                unsafe { linInitializeLibrary() };
+        log::info!("Opening {name} with device_id {device_id} and channel_id {channel_id}");
+
+        let mut channel = Channel::open(channel_id, host_mode).map_err(|e| {
+            let message = if e.is_not_found() {
+                format!(
+                    "Bus {channel_id} ({name}) not found - is the LIN bus powered up with 12 V? ({e})"
+                )
+            } else {
+                format!("Failed to open channel {channel_id} ({name}) - {e}")
+            };
+            failed(e, message)
+        })?;
 
-                let id_map = make_device_id_to_channel_id_map()?;
+        // The channel is closed again when dropped on failure
+        Self::configure(&mut channel, baudrate, model).map_err(|e| {
+            let message = format!("configuration of {name} failed: {e}");
+            failed(e, message)
+        })?;
 
-                log::info!("Found devices {id_map:?}");
+        Ok(KvaserLinux {
+            name: name.to_string(),
+            channel,
+            model: model.clone(),
+        })
+    }
 
-                Ok(id_map)
-            })
-            .as_ref()
-            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+    /// Block until a frame is read or timeout passes
+    pub fn read_wait(&mut self, timeout: Duration) -> Result<Option<Frame>> {
+        self.read(Some(timeout))
+    }
 
-        let channel_id = id_map.get(device_id).copied().ok_or_else(|| {
-            anyhow::anyhow!("Kvaser device with device_id {} not found", device_id)
+    fn read(&mut self, timeout: Option<Duration>) -> Result<Option<Frame>> {
+        let frame = self.channel.read(timeout).map_err(|e| {
+            let message = format!("Failed to read {} - {e}", self.name);
+            failed(e, message)
         })?;
 
-        log::info!("Opening {name} with device_id {device_id} and channel_id {channel_id}");
+        // Wake-up frames have no id, they are represented by the wake-up pseudo id on the vbus
+        Ok(frame.map(|mut frame| {
+            if frame
+                .info
+                .is_some_and(|info| info.flags.contains(FrameFlags::WAKEUP_FRAME))
+            {
+                frame.id = frame::WAKEUP_ID;
+                frame.msg.clear();
+            }
+            frame
+        }))
+    }
 
-        let native_host_mode = if host_mode == HostMode::Slave {
-            LIN_SLAVE
-        } else {
-            LIN_MASTER
-        } as i32;
-
-        let handle = unsafe { linOpenChannel(channel_id, native_host_mode) };
-
-        match handle {
-            handle if handle >= 0 => match Self::configure(handle, baudrate) {
-                Ok(()) => Ok(KvaserLinux {
-                    name: name.to_string(),
-                    handle,
-                    _not_send: PhantomData,
-                }),
-
-                Err(err) => {
-                    unsafe { linClose(handle) };
-                    Err(anyhow::anyhow!("configuration of {} failed: {}", name, err))
-                }
-            },
-            LinStatus_linERR_NOTFOUND => Err(anyhow::anyhow!(
-                "Bus {} ({}) not found - is the LIN bus powered up with 12 V? (error {})",
-                channel_id,
-                name,
-                handle
-            )),
-
-            _ => Err(anyhow::anyhow!(
-                "Failed to open channel {} ({}) (error {})",
-                channel_id,
-                name,
-                handle
-            )),
-        }
+    /// Stop answering the header of a frame
+    pub fn clear(&mut self, id: u32) -> Result<()> {
+        self.channel
+            .clear_message(id)
+            .map_err(|e| anyhow::anyhow!("Failed to clear response of frame id {id:#x} - {e}"))
     }
 
-    fn configure(handle: LinHandle, baudrate: u32) -> Result<()> {
-        unsafe {
-            linBusOff(handle);
+    /// Send a wake-up frame, by master or slave
+    pub fn wakeup(&mut self) -> Result<()> {
+        log::info!("{} sending wake-up", self.name);
 
-            let mut res = linBusOff(handle);
-            if res != LinStatus_linOK {
-                return Err(anyhow::anyhow!("linBusOff failed with {}", res));
-            }
+        self.channel
+            .write_wakeup(WAKEUP_COUNT, WAKEUP_INTERVAL)
+            .map_err(|e| anyhow::anyhow!("Failed to send wake-up - {e}"))
+    }
 
-            res = linSetBitrate(handle, baudrate);
-            if res != LinStatus_linOK {
-                return Err(anyhow::anyhow!("linSetBitrate failed with {}", res));
-            }
+    fn configure(channel: &mut Channel, baudrate: u32, model: &FrameModel) -> Result<(), LinError> {
+        // The first bus off is allowed to fail
+        let _ = channel.bus_off();
 
-            res = linBusOn(handle);
-            if res != LinStatus_linOK {
-                return Err(anyhow::anyhow!("linBusOn failed with {}", res));
-            }
+        channel.bus_off()?;
+        channel.set_bitrate(baudrate)?;
+        channel.bus_on()?;
 
-            res = linSetupLIN(handle, LIN_VARIABLE_DLC | LIN_ENHANCED_CHECKSUM, baudrate);
-            if res != LinStatus_linOK {
-                return Err(anyhow::anyhow!("linSetupLIN failed with {}", res));
-            }
+        let checksum = match model.checksum {
+            Checksum::Classic => SetupFlags::empty(),
+            Checksum::Enhanced => SetupFlags::ENHANCED_CHECKSUM,
+        };
+        let dlc = match model.dlc {
+            Dlc::Variable => SetupFlags::VARIABLE_DLC,
+            Dlc::Identifier => SetupFlags::empty(),
+        };
+        channel.setup(checksum | dlc, baudrate)?;
+
+        for (id, checksum) in model.checksum_exceptions() {
+            channel.setup_message(id, MessageFlags::from(checksum))?;
         }
 
         Ok(())
     }
+
+    /// Response of a frame padded or truncated to the length configured for its id
+    fn payload<'a>(&self, frame: &'a Frame) -> Cow<'a, [u8]> {
+        match self.model.length_of(frame.id) {
+            Some(length) if length != frame.msg.len() => {
+                let mut msg = frame.msg.clone();
+                msg.resize(length, 0xff);
+                Cow::Owned(msg)
+            }
+            _ => Cow::Borrowed(&frame.msg),
+        }
+    }
 }
 
 impl FrameReader for KvaserLinux {
@@ -135,125 +267,134 @@ impl FrameReader for KvaserLinux {
     }
 
     fn try_read(&mut self) -> Option<Frame> {
-        let mut msg: [u8; 16] = [0; 16];
-
-        let mut id: u32 = 0;
-        let mut msg_len: u32 = 0;
-
-        let mut info: LinMessageInfo = unsafe { zeroed() };
-
-        let res = unsafe {
-            linReadMessage(
-                self.handle,
-                &mut id,
-                msg.as_mut_ptr() as *mut c_void,
-                &mut msg_len,
-                std::ptr::null_mut(),
-                &mut info,
-            )
-        };
-
-        if res != LinStatus_linOK {
-            return None;
-        }
-
-        let mut msg = msg.to_vec();
-        msg.truncate(msg_len as usize);
-
-        Some(Frame { id, msg })
+        self.read(None).ok().flatten()
     }
 }
 
 impl Slave for KvaserLinux {
     fn update(&mut self, f: &Frame) -> Result<()> {
-        let msg_data: *const c_void = f.msg.as_ptr() as *const c_void;
-
         log::debug!("Updating frame {:x} with {:?}", f.id, f.msg);
 
-        let res = unsafe { linUpdateMessage(self.handle, f.id, msg_data, f.msg.len() as u32) };
-
-        if res != LinStatus_linOK {
-            return Err(anyhow::anyhow!(
-                "update failed to update using frame {:?} (err {})",
-                f,
-                res
-            ));
-        }
-
-        Ok(())
+        self.channel
+            .update(f.id, &self.payload(f))
+            .map_err(|e| anyhow::anyhow!("update failed to update using frame {f:?} - {e}"))
     }
 }
 
 impl Master for KvaserLinux {
     fn write(&mut self, frame: &Frame) -> Result<()> {
-        let msg_data: *const c_void = frame.msg.as_ptr() as *const c_void;
-
-        let res =
-            unsafe { linWriteMessage(self.handle, frame.id, msg_data, frame.msg.len() as u32) };
-
-        if res != LinStatus_linOK {
-            return Err(anyhow::anyhow!(
-                "Failed to write frame {:?} (err {})",
-                frame,
-                res
-            ));
-        }
+        // Empty frames are headers and wake-ups, they are never padded
+        let payload = if frame.msg.is_empty() {
+            Cow::Borrowed(frame.msg.as_slice())
+        } else {
+            self.payload(frame)
+        };
 
-        Ok(())
+        self.channel
+            .write(frame.id, &payload)
+            .map_err(|e| anyhow::anyhow!("Failed to write frame {frame:?} - {e}"))
     }
 
     fn request_update(&mut self, id: u32) -> Result<()> {
-        let res = unsafe { linRequestMessage(self.handle, id) };
+        self.channel
+            .request(id)
+            .map_err(|e| anyhow::anyhow!("Failed to request payload for id {id:?} - {e}"))
+    }
+}
 
-        if res != LinStatus_linOK {
-            return Err(anyhow::anyhow!(
-                "Failed to request payload for id {:?} (err {})",
-                id,
-                res
-            ));
-        }
+impl Probe for KvaserLinux {
+    fn probe(&mut self, id: u32, timeout: Duration) -> Result<Option<(Vec<u8>, u8)>> {
+        self.request_update(id)?;
 
-        Ok(())
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            let Some(frame) = self.read_wait(remaining)? else {
+                return Ok(None);
+            };
+
+            // Skip anything else still queued on the channel
+            if frame.id != id {
+                continue;
+            }
+
+            return Ok(match frame.info {
+                Some(info) if !frame.msg.is_empty() && !info.flags.contains(FrameFlags::NODATA) => {
+                    Some((frame.msg, info.checksum))
+                }
+                _ => None,
+            });
+        }
     }
 }
 
 impl Drop for KvaserLinux {
     fn drop(&mut self) {
         log::info!("KvaserLinux::drop {}", self.name);
-
-        unsafe { linClose(self.handle) };
     }
 }
 
-fn make_device_id_to_channel_id_map() -> Result<HashMap<String, i32>> {
-    let mut num_chans: i32 = 0;
-    let res = unsafe { canGetNumberOfChannels(&mut num_chans as *mut i32) };
+fn validate(model: &FrameModel) -> Result<()> {
+    for rule in &model.frames {
+        if rule.frame_id > MAX_ID {
+            return Err(anyhow::anyhow!(
+                "Invalid frame_id {:#x} in frames, LIN ids are at most {MAX_ID:#x}",
+                rule.frame_id
+            ));
+        }
 
-    if res != canStatus_canOK {
-        return Err(anyhow::anyhow!(
-            "canGetNumberOfChannels failed with {}",
-            res
-        ));
+        if rule
+            .length
+            .is_some_and(|length| length == 0 || length > MAX_LENGTH)
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid length for frame_id {:#x} in frames, must be 1 to {MAX_LENGTH}",
+                rule.frame_id
+            ));
+        }
     }
 
+    Ok(())
+}
+
+/// Error with the driver error as its source, so its status can still be checked
+fn failed(err: LinError, message: String) -> anyhow::Error {
+    anyhow::Error::new(err).context(message)
+}
+
+/// Whether opening or reading failed because the LIN bus has no supply
+pub fn is_no_power(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<LinError>())
+        .any(LinError::is_no_power)
+}
+
+fn find_devices() -> Result<Vec<Device>> {
+    let num_chans = kvaser_lib::channel_count()?;
+
     log::info!("Found {num_chans} kvaser channels");
     struct SerialAndChan {
         raw_serial: [u32; 2],
         local_channel: u32,
     }
 
+    // Details only describe the channel, it can still be opened if they are missing
+    fn or_default<T: Default>(res: Result<T, LinError>, channel: i32) -> T {
+        res.unwrap_or_else(|e| {
+            log::warn!("Failed to read details of channel {channel} - {e}");
+            T::default()
+        })
+    }
+
     let mut prev_serial_and_chan: Option<SerialAndChan> = None;
-    let results: HashMap<String, i32> = (0..num_chans)
+    let results: Vec<Device> = (0..num_chans)
         .map(|i| {
-            let mut raw_serial: [u32; 2] = [0; 2];
-            unsafe {
-                canGetChannelData(
-                    i,
-                    canCHANNELDATA_CARD_SERIAL_NO as i32,
-                    raw_serial.as_mut_ptr() as *mut c_void,
-                    size_of_val(&raw_serial),
-                )
-            };
+            let raw_serial = or_default(kvaser_lib::card_serial(i), i);
 
             if let Some(prev_serial_and_chan) = prev_serial_and_chan.as_mut() {
                 if prev_serial_and_chan.raw_serial == raw_serial {
@@ -271,13 +412,23 @@ fn make_device_id_to_channel_id_map() -> Result<HashMap<String, i32>> {
                 local_channel: 1,
             });
 
-            (
-                format!(
+            let capabilities = kvaser_lib::capabilities(i);
+
+            Device {
+                device_id: format!(
                     "{}{}:{}",
                     raw_serial[1], raw_serial[0], prev_serial_and_chan.local_channel
                 ),
-                i,
-            )
+                channel: i,
+                card_channel: prev_serial_and_chan.local_channel,
+                card_type: or_default(kvaser_lib::card_type(i), i),
+                ean: or_default(kvaser_lib::card_ean(i), i),
+                serial: (u64::from(raw_serial[1]) << 32) | u64::from(raw_serial[0]),
+                firmware: or_default(kvaser_lib::card_firmware(i), i),
+                lin: capabilities.as_ref().is_ok_and(|c| c.lin()),
+                can: capabilities.as_ref().is_ok_and(|c| c.can()),
+                used_by: None,
+            }
         })
         .collect();
 
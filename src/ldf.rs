@@ -1,12 +1,17 @@
 use anyhow::Result;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 
+/// LIN protocol version written unless another one is set
+pub const PROTOCOL_VERSION: &str = "2.1";
+
 #[derive(Debug, PartialEq)]
 pub struct Header {
     pub baudrate: u32,
+    pub protocol_version: String,
 }
 
 #[derive(Debug, PartialEq)]
@@ -15,12 +20,53 @@ pub struct Nodes {
     pub base_tick_ms: u32,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub size: u8,
+    pub init_value: InitValue,
+    pub publisher: String,
+    pub subscribers: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum InitValue {
+    Scalar(u64),
+    Array(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FrameSignal {
+    pub name: String,
+    pub offset: u8,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Frame {
     pub name: String,
     pub id: u32,
     pub owner: String,
     pub size: u8,
+    pub signals: Vec<FrameSignal>,
+    /// Written as a comment above the frame, not read back
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ProductId {
+    pub supplier_id: u16,
+    pub function_id: u16,
+    pub variant: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NodeAttributes {
+    pub name: String,
+    pub response_error: Option<String>,
+    pub configured_nad: Option<u8>,
+    pub initial_nad: Option<u8>,
+    pub product_id: Option<ProductId>,
+    pub configurable_frames: Vec<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -39,23 +85,270 @@ pub struct ScheduleTable {
 pub struct LDF {
     pub header: Header,
     pub nodes: Nodes,
+    pub signals: HashMap<String, Signal>,
     pub frames: HashMap<String, Frame>,
+    pub node_attributes: HashMap<String, NodeAttributes>,
     pub schedule_tables: HashMap<String, ScheduleTable>,
 }
 
+impl LDF {
+    /// Find the signal signalling response errors for the node publishing frame, if that frame carries it
+    pub fn response_error_signal<'a>(
+        &'a self,
+        frame: &'a Frame,
+    ) -> Option<(&'a FrameSignal, &'a Signal)> {
+        let signal_name = self
+            .node_attributes
+            .get(&frame.owner)?
+            .response_error
+            .as_ref()?;
+
+        let frame_signal = frame.signals.iter().find(|s| &s.name == signal_name)?;
+
+        Some((frame_signal, self.signals.get(signal_name)?))
+    }
+
+    /// Frames published by node sorted by id, by every slave if no node is given
+    pub fn published_frames(&self, node: Option<&str>) -> Result<Vec<&Frame>> {
+        if let Some(node) = node
+            && node != self.nodes.master
+            && !self.frames.values().any(|frame| frame.owner == node)
+        {
+            return Err(anyhow::anyhow!(
+                "Node {node} publishes no frames in the LDF"
+            ));
+        }
+
+        let mut frames: Vec<&Frame> = self
+            .frames
+            .values()
+            .filter(|frame| match node {
+                Some(node) => frame.owner == node,
+                None => frame.owner != self.nodes.master,
+            })
+            .collect();
+        frames.sort_by_key(|frame| frame.id);
+
+        Ok(frames)
+    }
+
+    /// Payload of frame with every signal at its init value and unused bits recessive
+    pub fn init_payload(&self, frame: &Frame) -> Vec<u8> {
+        let mut msg = vec![0xff; frame.size as usize];
+
+        for frame_signal in &frame.signals {
+            let Some(signal) = self.signals.get(&frame_signal.name) else {
+                continue;
+            };
+
+            match &signal.init_value {
+                InitValue::Scalar(value) => {
+                    encode_signal(&mut msg, frame_signal.offset, signal.size, *value)
+                }
+                InitValue::Array(bytes) => {
+                    for (i, byte) in bytes.iter().enumerate() {
+                        let offset = frame_signal.offset as usize + i * 8;
+                        let size = (signal.size as usize).saturating_sub(i * 8).min(8);
+                        encode_signal(&mut msg, offset as u8, size as u8, *byte as u64);
+                    }
+                }
+            }
+        }
+
+        msg
+    }
+}
+
+/// Writes the LDF in the format read by parse_file, sections sorted for stable output
+impl fmt::Display for LDF {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "LIN_description_file;")?;
+        writeln!(
+            f,
+            "LIN_protocol_version = \"{}\";",
+            self.header.protocol_version
+        )?;
+        writeln!(
+            f,
+            "LIN_language_version = \"{}\";",
+            self.header.protocol_version
+        )?;
+        writeln!(
+            f,
+            "LIN_speed = {:.3} kbps;",
+            self.header.baudrate as f64 / 1000.0
+        )?;
+        writeln!(f)?;
+
+        let slaves: BTreeSet<&str> = self
+            .frames
+            .values()
+            .map(|frame| frame.owner.as_str())
+            .chain(self.signals.values().map(|s| s.publisher.as_str()))
+            .chain(self.node_attributes.keys().map(String::as_str))
+            .filter(|node| *node != self.nodes.master)
+            .collect();
+
+        writeln!(f, "Nodes {{")?;
+        writeln!(
+            f,
+            "\tMaster: {}, {:.3} ms, 0.000 ms;",
+            self.nodes.master, self.nodes.base_tick_ms as f32
+        )?;
+        if !slaves.is_empty() {
+            let slaves: Vec<&str> = slaves.into_iter().collect();
+            writeln!(f, "\tSlaves: {};", slaves.join(", "))?;
+        }
+        writeln!(f, "}}")?;
+        writeln!(f)?;
+
+        let mut signals: Vec<&Signal> = self.signals.values().collect();
+        signals.sort_by(|a, b| a.name.cmp(&b.name));
+
+        writeln!(f, "Signals {{")?;
+        for signal in signals {
+            let init_value = match &signal.init_value {
+                InitValue::Scalar(value) => value.to_string(),
+                InitValue::Array(bytes) => {
+                    let bytes: Vec<String> = bytes.iter().map(|b| format!("{b:#04x}")).collect();
+                    format!("{{{}}}", bytes.join(", "))
+                }
+            };
+
+            write!(
+                f,
+                "\t{}: {}, {}, {}",
+                signal.name, signal.size, init_value, signal.publisher
+            )?;
+            for subscriber in &signal.subscribers {
+                write!(f, ", {subscriber}")?;
+            }
+            writeln!(f, ";")?;
+        }
+        writeln!(f, "}}")?;
+        writeln!(f)?;
+
+        let mut frames: Vec<&Frame> = self.frames.values().collect();
+        frames.sort_by(|a, b| (a.id, &a.name).cmp(&(b.id, &b.name)));
+
+        writeln!(f, "Frames {{")?;
+        for frame in frames {
+            if let Some(comment) = &frame.comment {
+                writeln!(f, "\t// {comment}")?;
+            }
+            writeln!(
+                f,
+                "\t{}: {:#04x}, {}, {} {{",
+                frame.name, frame.id, frame.owner, frame.size
+            )?;
+            for signal in &frame.signals {
+                writeln!(f, "\t\t{}, {};", signal.name, signal.offset)?;
+            }
+            writeln!(f, "\t}}")?;
+        }
+        writeln!(f, "}}")?;
+
+        if !self.node_attributes.is_empty() {
+            let mut nodes: Vec<&NodeAttributes> = self.node_attributes.values().collect();
+            nodes.sort_by(|a, b| a.name.cmp(&b.name));
+
+            writeln!(f)?;
+            writeln!(f, "Node_attributes {{")?;
+            for node in nodes {
+                writeln!(f, "\t{} {{", node.name)?;
+                writeln!(
+                    f,
+                    "\t\tLIN_protocol = \"{}\";",
+                    self.header.protocol_version
+                )?;
+                if let Some(nad) = node.configured_nad {
+                    writeln!(f, "\t\tconfigured_NAD = {nad:#04x};")?;
+                }
+                if let Some(nad) = node.initial_nad {
+                    writeln!(f, "\t\tinitial_NAD = {nad:#04x};")?;
+                }
+                if let Some(product_id) = node.product_id {
+                    writeln!(
+                        f,
+                        "\t\tproduct_id = {:#x}, {:#x}, {};",
+                        product_id.supplier_id, product_id.function_id, product_id.variant
+                    )?;
+                }
+                if let Some(response_error) = &node.response_error {
+                    writeln!(f, "\t\tresponse_error = {response_error};")?;
+                }
+                if !node.configurable_frames.is_empty() {
+                    writeln!(f, "\t\tconfigurable_frames {{")?;
+                    for frame in &node.configurable_frames {
+                        writeln!(f, "\t\t\t{frame};")?;
+                    }
+                    writeln!(f, "\t\t}}")?;
+                }
+                writeln!(f, "\t}}")?;
+            }
+            writeln!(f, "}}")?;
+        }
+
+        let mut tables: Vec<&ScheduleTable> = self.schedule_tables.values().collect();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+
+        writeln!(f)?;
+        writeln!(f, "Schedule_tables {{")?;
+        for table in tables {
+            writeln!(f, "\t{} {{", table.name)?;
+            for item in &table.items {
+                writeln!(f, "\t\t{} delay {:.3} ms;", item.name, item.delay)?;
+            }
+            writeln!(f, "\t}}")?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// Write value into a signal of size bits starting at bit offset, LIN bit order (LSB first)
+pub fn encode_signal(msg: &mut [u8], offset: u8, size: u8, value: u64) {
+    for bit in 0..size as usize {
+        let pos = offset as usize + bit;
+        if let Some(byte) = msg.get_mut(pos / 8) {
+            if value >> bit & 1 == 1 {
+                *byte |= 1 << (pos % 8);
+            } else {
+                *byte &= !(1 << (pos % 8));
+            }
+        }
+    }
+}
+
+/// Read a signal of size bits starting at bit offset, LIN bit order (LSB first)
+pub fn decode_signal(msg: &[u8], offset: u8, size: u8) -> u64 {
+    (0..size as usize).fold(0, |value, bit| {
+        let pos = offset as usize + bit;
+        match msg.get(pos / 8) {
+            Some(byte) if byte >> (pos % 8) & 1 == 1 => value | 1 << bit,
+            _ => value,
+        }
+    })
+}
+
 fn parse_ldf_lines<I>(lines: &mut I) -> Result<LDF>
 where
     I: Iterator<Item = io::Result<String>>,
 {
     let baudrate_re = Regex::new(r"^LIN_speed = ([0-9]+\.[0-9]+) kbps;")?;
+    let protocol_version_re = Regex::new(r#"^LIN_protocol_version = "([^"]*)";"#)?;
 
     let mut ldf = LDF {
-        header: Header { baudrate: 0 },
+        header: Header {
+            baudrate: 0,
+            protocol_version: PROTOCOL_VERSION.to_string(),
+        },
         nodes: Nodes {
             base_tick_ms: 0,
             master: "".to_string(),
         },
+        signals: HashMap::new(),
         frames: HashMap::new(),
+        node_attributes: HashMap::new(),
         schedule_tables: HashMap::new(),
     };
 
@@ -66,14 +359,22 @@ where
             "Nodes {" => {
                 ldf.nodes = parse_nodes(lines)?;
             }
+            "Signals {" => {
+                ldf.signals = parse_signals(lines)?;
+            }
             "Frames {" => {
                 ldf.frames = parse_frames(lines)?;
             }
+            "Node_attributes {" => {
+                ldf.node_attributes = parse_node_attributes(lines)?;
+            }
             "Schedule_tables {" => ldf.schedule_tables = parse_schedule_tables(lines)?,
             _ => {
                 if let Some(m) = baudrate_re.captures(&line) {
                     let baudrate: f32 = m[1].parse()?;
                     ldf.header.baudrate = (baudrate * 1000.0) as u32;
+                } else if let Some(m) = protocol_version_re.captures(&line) {
+                    ldf.header.protocol_version = m[1].to_string();
                 }
             }
         }
@@ -110,6 +411,64 @@ where
     Err(anyhow::anyhow!("Nodes section never ended!"))
 }
 
+fn parse_signals<I>(lines: &mut I) -> Result<HashMap<String, Signal>>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    let mut signals: HashMap<String, Signal> = HashMap::new();
+
+    let signal_re =
+        Regex::new(r"^\s*(\w+):\s*(\d+),\s*(\{[^}]*\}|\w+),\s*(\w+)((?:,\s*\w+)*)\s*;")?;
+
+    for line in lines.by_ref() {
+        let line = line?;
+
+        if let Some(m) = signal_re.captures(&line) {
+            let name = m[1].to_string();
+
+            signals.insert(
+                name.clone(),
+                Signal {
+                    name,
+                    size: m[2].parse()?,
+                    init_value: parse_init_value(&m[3])?,
+                    publisher: m[4].to_string(),
+                    subscribers: m[5]
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                },
+            );
+        } else if line == "}" {
+            return Ok(signals);
+        }
+    }
+
+    Err(anyhow::anyhow!("Signals section never ended!"))
+}
+
+fn parse_init_value(value: &str) -> Result<InitValue> {
+    if let Some(array) = value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+        let bytes = array
+            .split(',')
+            .map(|b| parse_int(b.trim()).map(|b| b as u8))
+            .collect::<Result<Vec<u8>>>()?;
+
+        Ok(InitValue::Array(bytes))
+    } else {
+        Ok(InitValue::Scalar(parse_int(value)?))
+    }
+}
+
+fn parse_int(value: &str) -> Result<u64> {
+    match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None => Ok(value.parse()?),
+    }
+}
+
 fn parse_frames<I>(lines: &mut I) -> Result<HashMap<String, Frame>>
 where
     I: Iterator<Item = io::Result<String>>,
@@ -117,6 +476,9 @@ where
     let mut frames: HashMap<String, Frame> = HashMap::new();
 
     let frame_re = Regex::new(r"^\s*([A-Za-z0-9]+):\s+0x([0-9A-Fa-f]+),\s+(\w+),\s+(\d+)\s*\{")?;
+    let frame_signal_re = Regex::new(r"^\s*(\w+),\s*(\d+)\s*;")?;
+
+    let mut current_frame: Option<String> = None;
 
     for line in lines.by_ref() {
         let line = line?;
@@ -127,20 +489,109 @@ where
             frames.insert(
                 name.clone(),
                 Frame {
-                    name,
+                    name: name.clone(),
                     id: u32::from_str_radix(&m[2], 16)?,
                     owner: m[3].to_string(),
                     size: m[4].parse()?,
+                    signals: Vec::new(),
+                    comment: None,
                 },
             );
+
+            current_frame = Some(name);
+        } else if let Some(m) = frame_signal_re.captures(&line) {
+            if let Some(frame) = current_frame.as_ref().and_then(|n| frames.get_mut(n)) {
+                frame.signals.push(FrameSignal {
+                    name: m[1].to_string(),
+                    offset: m[2].parse()?,
+                });
+            }
         } else if line == "}" {
             return Ok(frames);
+        } else if line.trim() == "}" {
+            current_frame = None;
         }
     }
 
     Err(anyhow::anyhow!("Frames section never ended!"))
 }
 
+fn parse_node_attributes<I>(lines: &mut I) -> Result<HashMap<String, NodeAttributes>>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    let mut node_attributes: HashMap<String, NodeAttributes> = HashMap::new();
+
+    let node_re = Regex::new(r"^\s*(\w+)\s*\{")?;
+    let response_error_re = Regex::new(r"^\s*response_error\s*=\s*(\w+)\s*;")?;
+    let nad_re = Regex::new(r"^\s*(configured_NAD|initial_NAD)\s*=\s*(\w+)\s*;")?;
+    let product_id_re = Regex::new(r"^\s*product_id\s*=\s*(\w+),\s*(\w+)(?:,\s*(\w+))?\s*;")?;
+    let configurable_frame_re = Regex::new(r"^\s*(\w+)\s*(?:=\s*\w+\s*)?;")?;
+
+    let mut depth = 0;
+    let mut current_node: Option<NodeAttributes> = None;
+
+    for line in lines.by_ref() {
+        let line = line?;
+
+        if line.trim().ends_with('{') {
+            depth += 1;
+
+            if depth == 1
+                && let Some(m) = node_re.captures(&line)
+            {
+                current_node = Some(NodeAttributes {
+                    name: m[1].to_string(),
+                    response_error: None,
+                    configured_nad: None,
+                    initial_nad: None,
+                    product_id: None,
+                    configurable_frames: Vec::new(),
+                });
+            }
+        } else if line.trim() == "}" {
+            if depth == 0 {
+                return Ok(node_attributes);
+            }
+
+            depth -= 1;
+
+            if depth == 0
+                && let Some(node) = current_node.take()
+            {
+                node_attributes.insert(node.name.clone(), node);
+            }
+        } else if let Some(node) = current_node.as_mut() {
+            if depth == 2 {
+                // Inside configurable_frames, LIN 2.0 files also give a message id per frame
+                if let Some(m) = configurable_frame_re.captures(&line) {
+                    node.configurable_frames.push(m[1].to_string());
+                }
+            } else if let Some(m) = response_error_re.captures(&line) {
+                node.response_error = Some(m[1].to_string());
+            } else if let Some(m) = nad_re.captures(&line) {
+                let nad = Some(parse_int(&m[2])? as u8);
+
+                match &m[1] {
+                    "configured_NAD" => node.configured_nad = nad,
+                    _ => node.initial_nad = nad,
+                }
+            } else if let Some(m) = product_id_re.captures(&line) {
+                node.product_id = Some(ProductId {
+                    supplier_id: parse_int(&m[1])? as u16,
+                    function_id: parse_int(&m[2])? as u16,
+                    variant: match m.get(3) {
+                        Some(variant) => parse_int(variant.as_str())? as u8,
+                        None => 0,
+                    },
+                });
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!("Node_attributes section never ended!"))
+}
+
 fn parse_schedule_tables<I>(lines: &mut I) -> Result<HashMap<String, ScheduleTable>>
 where
     I: Iterator<Item = io::Result<String>>,
@@ -193,6 +644,12 @@ where
     Err(anyhow::anyhow!("Schedule_Table section never ended!"))
 }
 
+pub fn parse_str(content: &str) -> Result<LDF> {
+    let mut lines = content.lines().map(|line| Ok(line.to_string()));
+
+    parse_ldf_lines(&mut lines)
+}
+
 pub fn parse_file(ldf_path: &str) -> Result<LDF> {
     let file = File::open(ldf_path)?;
     let reader = BufReader::new(file);
@@ -202,6 +659,11 @@ pub fn parse_file(ldf_path: &str) -> Result<LDF> {
     parse_ldf_lines(&mut lines)
 }
 
+pub fn write_file(ldf: &LDF, ldf_path: &str) -> Result<()> {
+    std::fs::write(ldf_path, ldf.to_string())
+        .map_err(|e| anyhow::anyhow!("Failed to write {ldf_path} - {e}"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -211,7 +673,13 @@ mod tests {
     #[test]
     fn test_should_parse_ldf() {
         let ldf = parse_file("src/testdata/mini.ldf").unwrap();
-        assert_eq!(ldf.header, Header { baudrate: 19200 });
+        assert_eq!(
+            ldf.header,
+            Header {
+                baudrate: 19200,
+                protocol_version: "2.2".to_string()
+            }
+        );
 
         assert_eq!(
             ldf.nodes,
@@ -230,7 +698,12 @@ mod tests {
                         name: "Slave1LinFrame01".to_string(),
                         id: 49,
                         owner: "Slave1".to_string(),
-                        size: 7
+                        size: 7,
+                        signals: vec![FrameSignal {
+                            name: "TheSignal01".to_string(),
+                            offset: 0
+                        }],
+                        comment: None
                     }
                 ),
                 (
@@ -239,7 +712,12 @@ mod tests {
                         name: "MasterLinFrame01".to_string(),
                         id: 50,
                         owner: "TheMaster".to_string(),
-                        size: 8
+                        size: 8,
+                        signals: vec![FrameSignal {
+                            name: "TheMasterSignal01".to_string(),
+                            offset: 48
+                        }],
+                        comment: None
                     }
                 ),
                 (
@@ -248,7 +726,12 @@ mod tests {
                         name: "Slave2LinFrame02".to_string(),
                         id: 50,
                         owner: "Slave2".to_string(),
-                        size: 8
+                        size: 8,
+                        signals: vec![FrameSignal {
+                            name: "TheSignal02".to_string(),
+                            offset: 48
+                        }],
+                        comment: None
                     }
                 )
             ])
@@ -300,4 +783,93 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_should_parse_signals_and_node_attributes() {
+        let ldf = parse_file("simulator/simulator.ldf").unwrap();
+
+        assert_eq!(
+            ldf.signals.get("SomeQf"),
+            Some(&Signal {
+                name: "SomeQf".to_string(),
+                size: 2,
+                init_value: InitValue::Scalar(3),
+                publisher: "DEVS1".to_string(),
+                subscribers: vec!["DEVM".to_string()],
+            })
+        );
+
+        assert_eq!(
+            ldf.node_attributes.get("DEVS1"),
+            Some(&NodeAttributes {
+                name: "DEVS1".to_string(),
+                response_error: Some("ErrRespDEVS1".to_string()),
+                configured_nad: Some(0x86),
+                initial_nad: Some(0x86),
+                product_id: Some(ProductId {
+                    supplier_id: 0x7f,
+                    function_id: 0x204,
+                    variant: 0,
+                }),
+                configurable_frames: vec!["DEVMLIN01Fr01".to_string(), "DEVS1LIN01Fr1".to_string()],
+            })
+        );
+
+        let frame = ldf.frames.get("DEVS1LIN01Fr1").unwrap();
+        let (frame_signal, signal) = ldf.response_error_signal(frame).unwrap();
+        assert_eq!(frame_signal.offset, 18);
+        assert_eq!(signal.size, 1);
+    }
+
+    #[test]
+    fn test_init_payloads_of_published_frames() {
+        let ldf = parse_file("simulator/simulator.ldf").unwrap();
+
+        let frames = ldf.published_frames(Some("DEVS1")).unwrap();
+        assert_eq!(frames.len(), 1);
+
+        // SomeQf is initially 3, the unused bits are recessive
+        assert_eq!(ldf.init_payload(frames[0]), vec![0x00, 0xc0, 0xfb]);
+
+        let ids: Vec<u32> = ldf
+            .published_frames(None)
+            .unwrap()
+            .iter()
+            .map(|frame| frame.id)
+            .collect();
+        assert_eq!(ids, vec![0x05, 0x10]);
+
+        assert!(ldf.published_frames(Some("DEVS9")).is_err());
+    }
+
+    #[test]
+    fn test_parse_array_init_value() {
+        assert_eq!(
+            parse_init_value("{0x01, 2, 0xFF}").unwrap(),
+            InitValue::Array(vec![1, 2, 255])
+        );
+        assert_eq!(parse_init_value("0x10").unwrap(), InitValue::Scalar(16));
+    }
+
+    #[test]
+    fn test_encode_and_decode_signal() {
+        let mut msg = vec![0u8; 3];
+
+        encode_signal(&mut msg, 6, 4, 0b1011);
+        assert_eq!(msg, vec![0b1100_0000, 0b0000_0010, 0]);
+        assert_eq!(decode_signal(&msg, 6, 4), 0b1011);
+
+        encode_signal(&mut msg, 18, 1, 1);
+        assert_eq!(msg[2], 0b0000_0100);
+        assert_eq!(decode_signal(&msg, 18, 1), 1);
+    }
+
+    #[test]
+    fn test_written_ldf_parses_back() {
+        for path in ["simulator/simulator.ldf", "src/testdata/mini.ldf"] {
+            let ldf = parse_file(path).unwrap();
+
+            assert_eq!(parse_str(&ldf.to_string()).unwrap(), ldf);
+        }
+    }
 }
@@ -0,0 +1,473 @@
+//! Best-effort LDF learned from passively observed LIN traffic.
+//!
+//! The learner collects the ids, lengths and periods of the frames seen on the bus, which of their
+//! bytes change, and the order they are scheduled in. Publishers cannot be told apart by a passive
+//! node, so every learned frame is published by the master.
+use crate::clock::Clock;
+use crate::frame::{self, Frame};
+use crate::ldf;
+use crate::masterslave::{BusEvent, FrameReader, Slave};
+use crate::msg;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Frames kept for inferring the schedule, enough for many cycles of a typical table
+const SEQUENCE_LIMIT: usize = 4096;
+
+/// Share of slots allowed to differ between cycles, e.g. for sporadic frames
+const SCHEDULE_TOLERANCE: f64 = 0.1;
+
+pub const MASTER_NAME: &str = "Master";
+pub const SLAVE_NAME: &str = "Slave";
+pub const SCHEDULE_TABLE_NAME: &str = "LearnedSchedule";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LearnedFrame {
+    pub id: u32,
+    pub length: usize,
+    /// Mean time between occurrences, None if seen once
+    pub period: Option<Duration>,
+    /// First payload seen
+    pub initial: Vec<u8>,
+    /// Indices of the bytes that differed from the first payload
+    pub changing_bytes: Vec<usize>,
+}
+
+struct Observed {
+    initial: Vec<u8>,
+    changed: Vec<u8>,
+    last_seen: Duration,
+    /// Sum and number of the gaps between occurrences, gaps going back in time are left out
+    gaps: Duration,
+    gap_count: u32,
+}
+
+pub struct Learner {
+    baudrate: u32,
+    frames: BTreeMap<u32, Observed>,
+    sequence: Vec<(Duration, u32)>,
+}
+
+impl Learner {
+    pub fn new(baudrate: u32) -> Self {
+        Learner {
+            baudrate,
+            frames: BTreeMap::new(),
+            sequence: Vec::new(),
+        }
+    }
+
+    /// Record a frame read from the bus at time, headers without response and diagnostics are skipped
+    pub fn observe(&mut self, time: Duration, f: &Frame) {
+        if f.msg.is_empty() || f.id >= frame::MASTER_REQ_ID {
+            return;
+        }
+
+        let observed = self.frames.entry(f.id).or_insert_with(|| Observed {
+            initial: f.msg.clone(),
+            changed: vec![0; f.msg.len()],
+            last_seen: time,
+            gaps: Duration::ZERO,
+            gap_count: 0,
+        });
+
+        if observed.changed.len() < f.msg.len() {
+            observed.changed.resize(f.msg.len(), 0);
+        }
+        for (i, byte) in f.msg.iter().enumerate() {
+            observed.changed[i] |= byte ^ observed.initial.get(i).copied().unwrap_or(0);
+        }
+
+        // Timestamps restart when the interface is reopened
+        if let Some(gap) = time.checked_sub(observed.last_seen)
+            && !gap.is_zero()
+        {
+            observed.gaps += gap;
+            observed.gap_count += 1;
+        }
+        observed.last_seen = time;
+
+        if self.sequence.len() < SEQUENCE_LIMIT {
+            self.sequence.push((time, f.id));
+        }
+    }
+
+    pub fn frames(&self) -> Vec<LearnedFrame> {
+        self.frames
+            .iter()
+            .map(|(&id, observed)| LearnedFrame {
+                id,
+                length: observed.changed.len(),
+                period: (observed.gap_count > 0).then(|| observed.gaps / observed.gap_count),
+                initial: observed.initial.clone(),
+                changing_bytes: (0..observed.changed.len())
+                    .filter(|&i| observed.changed[i] != 0)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Slots of the repeating schedule with the delay until the next slot
+    ///
+    /// Falls back to the order frames were first seen if the traffic does not repeat.
+    pub fn schedule(&self) -> Vec<(u32, Duration)> {
+        let ids: Vec<u32> = self.sequence.iter().map(|(_, id)| *id).collect();
+        let n = ids.len();
+
+        let cycle = (1..=n / 2).find(|&p| {
+            let mismatches = (0..n - p).filter(|&i| ids[i] != ids[i + p]).count();
+            mismatches as f64 <= (n - p) as f64 * SCHEDULE_TOLERANCE
+        });
+
+        let mean_gap = |gaps: &[Duration]| match gaps.len() {
+            0 => Duration::ZERO,
+            len => gaps.iter().sum::<Duration>() / len as u32,
+        };
+
+        // Gaps going back in time are unknown, they keep their slot but are not averaged
+        let gaps: Vec<Option<Duration>> = self
+            .sequence
+            .windows(2)
+            .map(|w| w[1].0.checked_sub(w[0].0))
+            .collect();
+
+        match cycle {
+            Some(p) => (0..p)
+                .map(|slot| {
+                    let slot_gaps: Vec<Duration> = gaps
+                        .iter()
+                        .skip(slot)
+                        .step_by(p)
+                        .flatten()
+                        .copied()
+                        .collect();
+                    (ids[slot], mean_gap(&slot_gaps))
+                })
+                .collect(),
+
+            None => {
+                let gaps: Vec<Duration> = gaps.iter().flatten().copied().collect();
+                let delay = mean_gap(&gaps);
+                self.frames.keys().map(|&id| (id, delay)).collect()
+            }
+        }
+    }
+
+    pub fn to_ldf(&self, base_tick_ms: u32) -> ldf::LDF {
+        let frames = self.frames();
+
+        let schedule = self
+            .schedule()
+            .into_iter()
+            .map(|(id, delay)| {
+                // Delays are whole milliseconds, at least a base tick
+                let delay_ms = (delay.as_secs_f64() * 1000.0).round() as u32;
+                (id, delay_ms.max(base_tick_ms))
+            })
+            .collect::<Vec<_>>();
+
+        skeleton(
+            self.baudrate,
+            base_tick_ms,
+            MASTER_NAME,
+            frames
+                .iter()
+                .map(|f| (f.id, f.initial.as_slice(), f.length)),
+            SCHEDULE_TABLE_NAME,
+            &schedule,
+        )
+    }
+}
+
+/// LDF with a frame per id named after it, and a placeholder byte signal per data byte
+/// initialised to the given payload
+pub fn skeleton<'a>(
+    baudrate: u32,
+    base_tick_ms: u32,
+    publisher: &str,
+    frames: impl Iterator<Item = (u32, &'a [u8], usize)>,
+    schedule_table_name: &str,
+    schedule: &[(u32, u32)],
+) -> ldf::LDF {
+    let subscriber = if publisher == MASTER_NAME {
+        SLAVE_NAME
+    } else {
+        MASTER_NAME
+    };
+
+    let mut ldf = ldf::LDF {
+        header: ldf::Header {
+            baudrate,
+            protocol_version: ldf::PROTOCOL_VERSION.to_string(),
+        },
+        nodes: ldf::Nodes {
+            master: MASTER_NAME.to_string(),
+            base_tick_ms,
+        },
+        signals: HashMap::new(),
+        frames: HashMap::new(),
+        node_attributes: HashMap::new(),
+        schedule_tables: HashMap::new(),
+    };
+
+    for (id, initial, length) in frames {
+        let frame_signals = (0..length)
+            .map(|byte| {
+                let name = signal_name(id, byte);
+
+                ldf.signals.insert(
+                    name.clone(),
+                    ldf::Signal {
+                        name: name.clone(),
+                        size: 8,
+                        init_value: ldf::InitValue::Scalar(
+                            initial.get(byte).copied().unwrap_or(0).into(),
+                        ),
+                        publisher: publisher.to_string(),
+                        subscribers: vec![subscriber.to_string()],
+                    },
+                );
+
+                ldf::FrameSignal {
+                    name,
+                    offset: (byte * 8) as u8,
+                }
+            })
+            .collect();
+
+        ldf.frames.insert(
+            frame_name(id),
+            ldf::Frame {
+                name: frame_name(id),
+                id,
+                owner: publisher.to_string(),
+                size: length as u8,
+                signals: frame_signals,
+                comment: None,
+            },
+        );
+    }
+
+    ldf.schedule_tables.insert(
+        schedule_table_name.to_string(),
+        ldf::ScheduleTable {
+            name: schedule_table_name.to_string(),
+            items: schedule
+                .iter()
+                .map(|&(id, delay_ms)| ldf::ScheduleTableItem {
+                    name: frame_name(id),
+                    delay: delay_ms as f32,
+                })
+                .collect(),
+        },
+    );
+
+    ldf
+}
+
+fn frame_name(id: u32) -> String {
+    format!("Frame{id:02X}")
+}
+
+fn signal_name(id: u32, byte: usize) -> String {
+    format!("Frame{id:02X}Byte{byte}")
+}
+
+/// Slave recording the traffic it reads while passing everything through
+pub struct Learning<S: Slave> {
+    target: S,
+    learner: Learner,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S: Slave> Learning<S> {
+    pub fn new(target: S, baudrate: u32, clock: Arc<dyn Clock>) -> Self {
+        Learning {
+            target,
+            learner: Learner::new(baudrate),
+            clock,
+        }
+    }
+
+    pub fn write_ldf(&self, path: &str, base_tick_ms: u32) -> Result<()> {
+        for f in self.learner.frames() {
+            log::info!(
+                "{} learned frame {:#x} with {} bytes every {:?}, changing bytes {:?}",
+                self.target.name(),
+                f.id,
+                f.length,
+                f.period,
+                f.changing_bytes
+            );
+        }
+
+        ldf::write_file(&self.learner.to_ldf(base_tick_ms), path)?;
+
+        log::info!("{} wrote learned LDF to {path}", self.target.name());
+
+        Ok(())
+    }
+}
+
+impl<S: Slave> FrameReader for Learning<S> {
+    fn name(&self) -> &str {
+        self.target.name()
+    }
+
+    fn try_read(&mut self) -> Option<Frame> {
+        let f = self.target.try_read()?;
+
+        // Hardware timestamps are not delayed by polling, frames received with errors are skipped
+        match &f.info {
+            Some(info) if info.flags.is_error() => {}
+            Some(info) => self
+                .learner
+                .observe(Duration::from_millis(info.timestamp), &f),
+            None => self.learner.observe(self.clock.now(), &f),
+        }
+
+        Some(f)
+    }
+
+    fn switch_schedule(&mut self, switch: &msg::SwitchSchedule) -> Result<()> {
+        self.target.switch_schedule(switch)
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        self.target.sleep()
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        self.target.wakeup()
+    }
+
+    fn try_read_event(&mut self) -> Option<BusEvent> {
+        self.target.try_read_event()
+    }
+
+    fn close(&mut self) -> Option<JoinHandle<()>> {
+        self.target.close()
+    }
+}
+
+impl<S: Slave> Slave for Learning<S> {
+    fn update(&mut self, f: &Frame) -> Result<()> {
+        self.target.update(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn frame(id: u32, msg: &[u8]) -> Frame {
+        Frame::new(id, msg.to_vec())
+    }
+
+    // Two cycles of 0x10, 0x20, 0x10, 0x21 in 10 ms slots with a rolling counter in 0x10
+    fn observe_table(learner: &mut Learner, cycles: u8) {
+        let mut time = Duration::ZERO;
+
+        for cycle in 0..cycles {
+            for f in [
+                frame(0x10, &[cycle, 0xaa]),
+                frame(0x20, &[1]),
+                frame(0x10, &[cycle, 0xaa]),
+                frame(0x21, &[]),
+            ] {
+                learner.observe(time, &f);
+                time += Duration::from_millis(10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_learns_frames() {
+        let mut learner = Learner::new(19_200);
+        observe_table(&mut learner, 4);
+
+        assert_eq!(
+            learner.frames(),
+            vec![
+                LearnedFrame {
+                    id: 0x10,
+                    length: 2,
+                    period: Some(Duration::from_millis(20)),
+                    initial: vec![0, 0xaa],
+                    changing_bytes: vec![0],
+                },
+                LearnedFrame {
+                    id: 0x20,
+                    length: 1,
+                    period: Some(Duration::from_millis(40)),
+                    initial: vec![1],
+                    changing_bytes: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_infers_schedule() {
+        let mut learner = Learner::new(19_200);
+        observe_table(&mut learner, 4);
+
+        // The unanswered header of 0x21 is not seen, its slot time goes to the one before
+        assert_eq!(
+            learner.schedule(),
+            vec![
+                (0x10, Duration::from_millis(10)),
+                (0x20, Duration::from_millis(10)),
+                (0x10, Duration::from_millis(20)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backwards_timestamp_is_skipped() {
+        let mut learner = Learner::new(19_200);
+
+        // The interface was reopened between the second and the third frame
+        for (ms, f) in [
+            (100, frame(0x10, &[1])),
+            (110, frame(0x10, &[1])),
+            (5, frame(0x10, &[1])),
+            (15, frame(0x10, &[1])),
+        ] {
+            learner.observe(Duration::from_millis(ms), &f);
+        }
+
+        assert_eq!(learner.frames()[0].period, Some(Duration::from_millis(10)));
+        assert_eq!(learner.schedule(), vec![(0x10, Duration::from_millis(10))]);
+    }
+
+    #[test]
+    fn test_learned_ldf_parses() {
+        let mut learner = Learner::new(19_200);
+        observe_table(&mut learner, 4);
+
+        let ldf = ldf::parse_str(&learner.to_ldf(5).to_string()).unwrap();
+
+        let frame = ldf.frames.get("Frame10").unwrap();
+        assert_eq!(
+            (frame.id, frame.size, frame.owner.as_str()),
+            (0x10, 2, "Master")
+        );
+        assert_eq!(
+            ldf.signals.get("Frame10Byte1").unwrap().init_value,
+            ldf::InitValue::Scalar(0xaa)
+        );
+
+        let delays: Vec<f32> = ldf.schedule_tables[SCHEDULE_TABLE_NAME]
+            .items
+            .iter()
+            .map(|item| item.delay)
+            .collect();
+        assert_eq!(delays, vec![10.0, 10.0, 20.0]);
+    }
+}
@@ -1,14 +1,28 @@
 //! Library for remotivebus-kvaser providing shared modules for both remotivebus-kvaser and utils (src/bin).
+pub mod clock;
+pub mod diag;
+pub mod e2e;
+pub mod fault;
 pub mod frame;
+pub mod health;
 pub mod ldf;
+pub mod learn;
 pub mod logging;
 pub mod masterslave;
 pub mod msg;
 pub mod noechoslave;
+pub mod replay;
+pub mod scan;
+pub mod script;
 pub mod server;
 pub mod simulator;
+pub mod timing;
+pub mod virtual_lin;
 pub mod worker;
 
+pub mod kvaser_channel;
+pub mod kvaser_device;
+pub mod kvaser_lib;
 pub mod kvaser_linux;
 pub use kvaser_linux as kvaser;
 pub mod kvaser_raw_binding;
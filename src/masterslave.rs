@@ -1,6 +1,26 @@
 use anyhow::Result;
+use tokio::task::JoinHandle;
 
 use crate::frame::Frame;
+use crate::health::Health;
+use crate::msg;
+
+/// Change of the connection to the bus
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusEvent {
+    /// The interface was lost, nothing is read or written until it is restored
+    Lost {
+        reason: String,
+        no_power: bool,
+    },
+    Restored,
+    /// Supervised health of the bus changed
+    Health(Health),
+    /// A go-to-sleep command put the bus to sleep
+    Asleep,
+    /// A wake-up frame or traffic woke the sleeping bus
+    Awake,
+}
 
 pub trait FrameReader {
     // Friendly name
@@ -8,6 +28,35 @@ pub trait FrameReader {
 
     // Poll and read if a frame is available
     fn try_read(&mut self) -> Option<Frame>;
+
+    // Switch schedule table at runtime, only supported by schedule-driven readers
+    fn switch_schedule(&mut self, _switch: &msg::SwitchSchedule) -> Result<()> {
+        Err(anyhow::anyhow!(
+            "{} does not support schedule switching",
+            self.name()
+        ))
+    }
+
+    // Put the bus to sleep, only supported by masters
+    fn sleep(&mut self) -> Result<()> {
+        Err(anyhow::anyhow!("{} does not support sleep", self.name()))
+    }
+
+    // Transmit a wake-up pulse on the bus
+    fn wakeup(&mut self) -> Result<()> {
+        Err(anyhow::anyhow!("{} does not support wake-up", self.name()))
+    }
+
+    // Poll for a change of the connection or bus state, only reported by hardware interfaces
+    fn try_read_event(&mut self) -> Option<BusEvent> {
+        None
+    }
+
+    // Release the interface, the handle finishes once it is free again. Only hardware interfaces
+    // hold one.
+    fn close(&mut self) -> Option<JoinHandle<()>> {
+        None
+    }
 }
 
 pub trait Slave: FrameReader {
@@ -1,3 +1,4 @@
+use crate::frame;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize)]
@@ -5,6 +6,8 @@ pub struct Response {
     pub success: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
 }
 
 impl Response {
@@ -12,12 +15,24 @@ impl Response {
         Response {
             success: true,
             error: None,
+            result: None,
+        }
+    }
+    pub fn ok_with(result: impl Serialize) -> Self {
+        match serde_json::to_value(result) {
+            Ok(result) => Response {
+                success: true,
+                error: None,
+                result: Some(result),
+            },
+            Err(e) => Response::err(format!("Failed to serialize result - {e}")),
         }
     }
     pub fn err(msg: impl ToString) -> Self {
         Response {
             success: false,
             error: Some(msg.to_string()),
+            result: None,
         }
     }
 }
@@ -29,6 +44,44 @@ pub enum Message {
     StartAction(Config),
     #[serde(rename = "stop")]
     StopAction(Config),
+    #[serde(rename = "switch_schedule")]
+    SwitchScheduleAction(SwitchSchedule),
+    #[serde(rename = "sleep")]
+    SleepAction(BusTarget),
+    #[serde(rename = "wakeup")]
+    WakeupAction(BusTarget),
+    #[serde(rename = "scan")]
+    ScanAction(Scan),
+    #[serde(rename = "list_devices")]
+    ListDevicesAction,
+    #[serde(rename = "status")]
+    StatusAction,
+}
+
+/// Running bus targeted by a runtime action
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BusTarget {
+    /// Host device of the running bus, e.g. "hostlin0"
+    pub host_device: String,
+}
+
+/// Discovery scan of a Kvaser channel not used by a running bus
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Scan {
+    /// LIN device id, e.g., "011121:1"
+    pub device_id: String,
+
+    /// Baudrate of the scanned bus in bits per second. Defaults to 19200 if not specified.
+    #[serde(default)]
+    pub baudrate: Baudrate,
+
+    /// Number of times each answered frame id is requested
+    #[serde(default = "default_scan_samples")]
+    pub samples: usize,
+
+    /// Include a skeleton LDF of the answered frames in the report
+    #[serde(default)]
+    pub ldf: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -36,9 +89,10 @@ pub struct Config {
     /// LIN host device name, e.g. "hostlin0"
     pub host_device: String,
 
-    /// Baudrate for the LIN device in bits per second. Defaults to 19200 if not specified.
+    /// Baudrate for the LIN device in bits per second, or "auto" to detect it on the bus.
+    /// Defaults to 19200 if not specified.
     #[serde(default)]
-    pub baudrate: Baudrate,
+    pub baudrate: BusBaudrate,
 
     /// remotivebus-kvaser specific configuration
     pub plugin: Plugin,
@@ -54,6 +108,33 @@ pub enum Plugin {
     /// Simulator plugin configuration
     #[serde(rename = "simulator")]
     Simulator(Simulator),
+
+    /// Replay plugin configuration
+    #[serde(rename = "replay")]
+    Replay(Replay),
+
+    /// Virtual LIN bus plugin configuration
+    #[serde(rename = "virtual")]
+    Virtual(Virtual),
+}
+
+impl Plugin {
+    /// Kvaser device id of the channel used by the bus, if any
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            Plugin::Lin(lin) => Some(&lin.device_id),
+            Plugin::Replay(replay) => replay.device_id.as_deref(),
+            Plugin::Simulator(_) | Plugin::Virtual(_) => None,
+        }
+    }
+
+    pub fn device_id_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Plugin::Lin(lin) => Some(&mut lin.device_id),
+            Plugin::Replay(replay) => replay.device_id.as_mut(),
+            Plugin::Simulator(_) | Plugin::Virtual(_) => None,
+        }
+    }
 }
 
 /// Custom deserialization that defaults to "lin" when type field is missing
@@ -73,6 +154,21 @@ impl<'de> Deserialize<'de> for Plugin {
                     serde_json::from_value(value).map_err(serde::de::Error::custom)?;
                 Ok(Plugin::Simulator(sim))
             }
+            Some("replay") => {
+                let replay: Replay =
+                    serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+                Ok(Plugin::Replay(replay))
+            }
+            Some("virtual") => {
+                // Virtual denies unknown fields, the tag is not one of its own
+                let mut value = value;
+                if let Some(fields) = value.as_object_mut() {
+                    fields.remove("type");
+                }
+                let virtual_lin: Virtual =
+                    serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+                Ok(Plugin::Virtual(virtual_lin))
+            }
             Some(other) => Err(serde::de::Error::custom(format!(
                 "unknown plugin type: {}",
                 other
@@ -97,12 +193,145 @@ pub struct Lin {
     /// LIN host mode ("master" or "slave")
     pub host_mode: HostMode,
 
-    /// LIN device id, e.g., "011121:1"
+    /// LIN device id, e.g., "011121:1", or a selector such as "serial:11121/ch:1" or "any-lin"
     pub device_id: String,
 
     /// LIN base tick in milliseconds
     #[serde(default)]
     pub base_tick_ms: BaseTick,
+
+    /// Path to write an LDF learned from the observed traffic to when the bus stops, slave only
+    pub learn: Option<String>,
+
+    /// Path to an LDF whose frames are answered with their init values from the start, slave only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database: Option<String>,
+
+    /// Node from database whose frames are answered. Defaults to every slave node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+
+    /// Time without traffic after which a slave reports the bus idle, 0 disables
+    #[serde(default = "default_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+
+    /// Checksum and length of the frames
+    #[serde(flatten)]
+    pub model: FrameModel,
+}
+
+/// Checksum and length of the frames on a Kvaser channel
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct FrameModel {
+    /// Checksum of every frame, diagnostic frames use classic unless configured in frames
+    #[serde(default)]
+    pub checksum: Checksum,
+
+    /// How the length of a response is determined
+    #[serde(default)]
+    pub dlc: Dlc,
+
+    /// Checksum and length of single frame ids
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<FrameRule>,
+}
+
+impl FrameModel {
+    pub fn checksum_of(&self, id: u32) -> Checksum {
+        let configured = self
+            .frames
+            .iter()
+            .rev()
+            .find_map(|rule| (rule.frame_id == id).then_some(rule.checksum?));
+
+        match configured {
+            Some(checksum) => checksum,
+            // Diagnostic frames always use the classic checksum
+            None if id == frame::MASTER_REQ_ID || id == frame::SLAVE_RESP_ID => Checksum::Classic,
+            None => self.checksum,
+        }
+    }
+
+    pub fn length_of(&self, id: u32) -> Option<usize> {
+        self.frames
+            .iter()
+            .rev()
+            .find_map(|rule| (rule.frame_id == id).then_some(rule.length?))
+    }
+
+    /// Frame ids whose checksum differs from the checksum of every frame
+    pub fn checksum_exceptions(&self) -> Vec<(u32, Checksum)> {
+        let mut ids: Vec<u32> = [frame::MASTER_REQ_ID, frame::SLAVE_RESP_ID]
+            .into_iter()
+            .chain(self.frames.iter().map(|rule| rule.frame_id))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter()
+            .map(|id| (id, self.checksum_of(id)))
+            .filter(|(_, checksum)| *checksum != self.checksum)
+            .collect()
+    }
+}
+
+/// Checksum and length of a single frame id
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct FrameRule {
+    /// Frame id, e.g. 60
+    pub frame_id: u32,
+
+    /// Checksum of the frame. Defaults to the checksum of every frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+
+    /// Response length in bytes, shorter responses are padded with 0xff and longer truncated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+}
+
+/// LIN checksum model ("classic" or "enhanced")
+#[derive(Debug, Default, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum Checksum {
+    /// LIN 1.x, over the data bytes only
+    #[serde(rename = "classic")]
+    Classic,
+    /// LIN 2.x, over the protected identifier and the data bytes
+    #[default]
+    #[serde(rename = "enhanced")]
+    Enhanced,
+}
+
+/// How the length of a response is determined ("variable" or "identifier")
+#[derive(Debug, Default, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum Dlc {
+    /// Any length from 1 to 8 bytes, as written
+    #[default]
+    #[serde(rename = "variable")]
+    Variable,
+    /// LIN 1.x, given by the identifier: 2 bytes below 0x20, 4 below 0x30, 8 above
+    #[serde(rename = "identifier")]
+    Identifier,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Virtual {
+    /// Virtual LIN driver name ("virtual")
+    pub driver: String,
+
+    /// Optional name for the virtual LIN interface. Defaults to device name.
+    pub name: Option<String>,
+
+    /// LIN host mode ("master" or "slave")
+    pub host_mode: HostMode,
+
+    /// Name of the in-process LIN bus, shared by every bus started with the same name
+    pub virtual_bus: String,
+
+    /// On-wire frame timing
+    #[serde(default)]
+    pub timing: Timing,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -122,12 +351,307 @@ pub struct Simulator {
 
     /// Path to LDF database file
     pub database: String,
+
+    /// Fault injection rules, applied to the simulated traffic
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub faults: Vec<FaultRule>,
+
+    /// Seed for probabilistic fault injection
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Clock driving the schedule ("wall" or "virtual"). Defaults to "wall".
+    #[serde(default)]
+    pub clock: ClockMode,
+
+    /// Virtual time per real time with the virtual clock, e.g. 10.0 runs ten times faster than
+    /// real time. Defaults to 1.0.
+    #[serde(default = "default_speed", deserialize_with = "deserialize_speed")]
+    pub speed: f64,
+
+    /// End-to-end protection rules, recomputed every time a frame is emitted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub e2e: Vec<E2eRule>,
+
+    /// Optional path to a Rhai script implementing node behaviour
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+
+    /// Simulated slaves answer the headers of their frames (host_mode master). Defaults to false.
+    #[serde(default)]
+    pub respond: bool,
+
+    /// On-wire frame timing
+    #[serde(default)]
+    pub timing: Timing,
+}
+
+/// On-wire LIN frame timing in bit times. Defaults to the nominal frame without spaces.
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct Timing {
+    /// Break field length, at least 13 bits
+    pub break_bits: f64,
+
+    /// Break delimiter length, at least 1 bit
+    pub break_delimiter_bits: f64,
+
+    /// Space between header and response
+    pub response_space_bits: f64,
+
+    /// Space between response bytes
+    pub inter_byte_space_bits: f64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            break_bits: 13.0,
+            break_delimiter_bits: 1.0,
+            response_space_bits: 0.0,
+            inter_byte_space_bits: 0.0,
+        }
+    }
+}
+
+/// Clock driving the simulator
+#[derive(Debug, Default, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum ClockMode {
+    /// Real time
+    #[default]
+    #[serde(rename = "wall")]
+    Wall,
+    /// Virtual time, advanced one base tick per step at the configured speed. Runs are reproducible.
+    #[serde(rename = "virtual")]
+    Virtual,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Replay {
+    /// Replay driver name ("kvaser")
+    pub driver: String,
+
+    /// Optional name for the replay interface. Defaults to "replay".
+    #[serde(default = "default_replay_name")]
+    pub name: String,
+
+    /// Path to recorded trace
+    pub trace: String,
+
+    /// Trace format. Detected from the trace file extension if not specified.
+    pub format: Option<TraceFormat>,
+
+    /// Kvaser device id to replay onto as LIN master, e.g. "011121:1". Replays onto the vbus if not specified.
+    pub device_id: Option<String>,
+
+    /// Restart from the beginning when the end of the trace is reached
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+
+    /// Playback speed factor, e.g. 2.0 plays twice as fast. Defaults to 1.0.
+    #[serde(default = "default_replay_speed")]
+    pub speed: f64,
+
+    /// Skip frames recorded before this offset (in ms) from the start of the trace
+    #[serde(default)]
+    pub start_offset_ms: u64,
+
+    /// Skip frames recorded after this offset (in ms) from the start of the trace
+    pub end_offset_ms: Option<u64>,
+}
+
+/// Recorded trace format
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum TraceFormat {
+    /// candump log, e.g. "(1436509052.249713) vcan0 011#0102"
+    #[serde(rename = "candump")]
+    Candump,
+    /// Vector ASC
+    #[serde(rename = "asc")]
+    Asc,
+    /// JSON lines, e.g. {"t": 0.01, "id": 17, "data": [1, 2]}
+    #[serde(rename = "jsonl")]
+    Jsonl,
+}
+
+/// Fault injected into simulated frames matching frame_id and/or slot
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FaultRule {
+    /// Frame id to match, e.g. 5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_id: Option<u32>,
+
+    /// Slot index in the running schedule table to match (simulated master only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<usize>,
+
+    /// Fault to inject
+    pub fault: Fault,
+
+    /// Probability (0.0 - 1.0) of injecting the fault on each match
+    #[serde(
+        default,
+        deserialize_with = "deserialize_probability",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub probability: Option<f64>,
+
+    /// Repeating pattern deciding whether to inject the fault on each match, e.g. [false, true]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<Vec<bool>>,
+}
+
+/// LIN fault to inject into a frame
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum Fault {
+    /// Response is lost, nothing is seen for the frame
+    #[serde(rename = "drop_response")]
+    DropResponse,
+    /// Response has a bad checksum and is discarded by receivers
+    #[serde(rename = "corrupt_checksum")]
+    CorruptChecksum,
+    /// Response is truncated or extended to the given length
+    #[serde(rename = "length")]
+    Length(usize),
+    /// Header is sent but nobody responds
+    #[serde(rename = "header_only")]
+    HeaderOnly,
+    /// Publishing node sets its response_error signal
+    #[serde(rename = "response_error")]
+    ResponseError,
+}
+
+/// End-to-end protection of a simulated frame
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct E2eRule {
+    /// Frame id to protect, e.g. 3
+    pub frame_id: u32,
+
+    /// Alive counter incremented every time the frame is emitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<CounterRule>,
+
+    /// Checksum computed after the counter has been updated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<ChecksumRule>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CounterRule {
+    /// Counter signal name from LDF file
+    pub signal: String,
+
+    /// Counter restarts at 0 when reaching this value. Defaults to the signal range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrap: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChecksumRule {
+    /// Checksum signal name from LDF file
+    pub signal: String,
+
+    /// Checksum algorithm ("xor", "sum" or "crc8")
+    pub algorithm: ChecksumAlgorithm,
+
+    /// Byte indexes covered by the checksum. Defaults to all bytes, with the checksum signal cleared.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum ChecksumAlgorithm {
+    #[serde(rename = "xor")]
+    Xor,
+    /// Sum modulo 256
+    #[serde(rename = "sum")]
+    Sum,
+    /// CRC8 SAE J1850 (polynomial 0x1d, initial value and final xor 0xff)
+    #[serde(rename = "crc8")]
+    Crc8,
+}
+
+/// Runtime schedule table switch for a running simulator bus
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SwitchSchedule {
+    /// Host device of the running bus, e.g. "hostlin0"
+    pub host_device: String,
+
+    /// Schedule table name from LDF file to switch to
+    pub schedule_table_name: String,
+
+    /// When the switch takes effect. Defaults to the end of the current slot.
+    #[serde(default)]
+    pub at: SwitchAt,
+
+    /// Run the table once and then return to the interrupted table, e.g. for diagnostics
+    #[serde(default)]
+    pub one_shot: bool,
+}
+
+/// When a schedule table switch takes effect ("end_of_slot" or "end_of_table")
+#[derive(Debug, Default, Deserialize, Serialize, Copy, Clone, PartialEq)]
+pub enum SwitchAt {
+    #[default]
+    #[serde(rename = "end_of_slot")]
+    EndOfSlot,
+    #[serde(rename = "end_of_table")]
+    EndOfTable,
+}
+
+fn deserialize_probability<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let probability = Option::<f64>::deserialize(deserializer)?;
+
+    match probability {
+        Some(p) if !(0.0..=1.0).contains(&p) => Err(serde::de::Error::custom(format!(
+            "probability {p} is not within 0.0 - 1.0"
+        ))),
+        _ => Ok(probability),
+    }
+}
+
+fn deserialize_speed<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let speed = f64::deserialize(deserializer)?;
+
+    if speed.is_finite() && speed > 0.0 {
+        Ok(speed)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "speed {speed} is not a positive number"
+        )))
+    }
+}
+
+fn default_speed() -> f64 {
+    1.0
 }
 
 fn default_simulator_name() -> String {
     "simulator".to_string()
 }
 
+fn default_replay_name() -> String {
+    "replay".to_string()
+}
+
+fn default_replay_speed() -> f64 {
+    1.0
+}
+
+fn default_scan_samples() -> usize {
+    3
+}
+
+fn default_idle_timeout_ms() -> u64 {
+    1000
+}
+
 /// LIN host mode ("master" or "slave")
 #[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq)]
 pub enum HostMode {
@@ -159,6 +683,31 @@ impl From<Baudrate> for u64 {
     }
 }
 
+/// LIN baudrate of a bus, either fixed or detected on the bus ("auto")
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum BusBaudrate {
+    Fixed(Baudrate),
+    Auto(Auto),
+}
+
+impl Default for BusBaudrate {
+    fn default() -> Self {
+        BusBaudrate::Fixed(Baudrate::default())
+    }
+}
+
+impl BusBaudrate {
+    pub const AUTO: BusBaudrate = BusBaudrate::Auto(Auto::Auto);
+}
+
+/// Marker of a baudrate detected on the bus
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Auto {
+    #[serde(rename = "auto")]
+    Auto,
+}
+
 /// LIN basetick in milliseconds. Default is 5 ms.
 #[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
 pub struct BaseTick(pub u32);
@@ -193,7 +742,7 @@ mod tests {
             panic!("Expected StartAction");
         };
         assert_eq!(config.host_device, "myhostvlin");
-        assert_eq!(config.baudrate, Baudrate(19_200));
+        assert_eq!(config.baudrate, BusBaudrate::Fixed(Baudrate(19_200)));
 
         let Plugin::Lin(lin) = config.plugin else {
             panic!("Expected Lin plugin");
@@ -213,7 +762,7 @@ mod tests {
             panic!("Expected StartAction");
         };
         assert_eq!(config.host_device, "myhostvlin");
-        assert_eq!(config.baudrate, Baudrate(9_600));
+        assert_eq!(config.baudrate, BusBaudrate::Fixed(Baudrate(9_600)));
 
         let Plugin::Lin(lin) = config.plugin else {
             panic!("Expected Lin plugin");
@@ -225,6 +774,97 @@ mod tests {
         assert_eq!(lin.base_tick_ms, BaseTick(5));
     }
 
+    #[test]
+    fn test_deserialize_idle_timeout() {
+        let json = r#"{
+            "driver": "kvaser",
+            "host_mode": "slave",
+            "device_id": "011121:1",
+            "idle_timeout_ms": 500
+        }"#;
+        let lin: Lin = serde_json::from_str(json).expect("Failed to parse");
+        assert_eq!(lin.idle_timeout_ms, 500);
+
+        let json = r#"{ "driver": "kvaser", "host_mode": "slave", "device_id": "011121:1" }"#;
+        let lin: Lin = serde_json::from_str(json).expect("Failed to parse");
+        assert_eq!(lin.idle_timeout_ms, 1000);
+    }
+
+    #[test]
+    fn test_deserialize_frame_model() {
+        let json = r#"{
+            "driver": "kvaser",
+            "host_mode": "slave",
+            "device_id": "011121:1",
+            "checksum": "classic",
+            "dlc": "identifier",
+            "frames": [
+                { "frame_id": 16, "checksum": "enhanced", "length": 4 },
+                { "frame_id": 61, "checksum": "enhanced" }
+            ]
+        }"#;
+        let lin: Lin = serde_json::from_str(json).expect("Failed to parse");
+
+        assert_eq!(lin.model.checksum, Checksum::Classic);
+        assert_eq!(lin.model.dlc, Dlc::Identifier);
+        assert_eq!(
+            lin.model.frames[0],
+            FrameRule {
+                frame_id: 16,
+                checksum: Some(Checksum::Enhanced),
+                length: Some(4),
+            }
+        );
+        assert_eq!(lin.model.length_of(16), Some(4));
+        assert_eq!(lin.model.length_of(61), None);
+        assert_eq!(lin.database, None);
+    }
+
+    #[test]
+    fn test_default_frame_model() {
+        let json = r#"{ "driver": "kvaser", "host_mode": "slave", "device_id": "011121:1" }"#;
+        let lin: Lin = serde_json::from_str(json).expect("Failed to parse");
+
+        assert_eq!(lin.model.checksum, Checksum::Enhanced);
+        assert_eq!(lin.model.dlc, Dlc::Variable);
+        assert!(lin.model.frames.is_empty());
+    }
+
+    #[test]
+    fn test_checksum_exceptions() {
+        let mut model = FrameModel::default();
+
+        // Diagnostic frames are classic by default
+        assert_eq!(model.checksum_of(0x10), Checksum::Enhanced);
+        assert_eq!(
+            model.checksum_exceptions(),
+            vec![(0x3c, Checksum::Classic), (0x3d, Checksum::Classic)]
+        );
+
+        model.frames = vec![
+            FrameRule {
+                frame_id: 0x3d,
+                checksum: Some(Checksum::Enhanced),
+                length: None,
+            },
+            FrameRule {
+                frame_id: 0x10,
+                checksum: Some(Checksum::Classic),
+                length: Some(2),
+            },
+        ];
+        assert_eq!(
+            model.checksum_exceptions(),
+            vec![(0x10, Checksum::Classic), (0x3c, Checksum::Classic)]
+        );
+
+        model.checksum = Checksum::Classic;
+        assert_eq!(
+            model.checksum_exceptions(),
+            vec![(0x3d, Checksum::Enhanced)]
+        );
+    }
+
     #[test]
     fn test_deserialize_stop_action() {
         let json = include_str!("testdata/stop.json");
@@ -235,7 +875,7 @@ mod tests {
         };
 
         assert_eq!(config.host_device, "myhostvlin");
-        assert_eq!(config.baudrate, Baudrate(19_200));
+        assert_eq!(config.baudrate, BusBaudrate::Fixed(Baudrate(19_200)));
         let Plugin::Lin(lin) = config.plugin else {
             panic!("Expected Lin plugin");
         };
@@ -245,17 +885,257 @@ mod tests {
         assert_eq!(lin.base_tick_ms, BaseTick(5));
     }
 
+    #[test]
+    fn test_deserialize_switch_schedule_action() {
+        let json = r#"{
+            "action": "switch_schedule",
+            "bus": {
+                "host_device": "lin0",
+                "schedule_table_name": "DiagSchedule",
+                "at": "end_of_table",
+                "one_shot": true
+            }
+        }"#;
+        let message: Message = serde_json::from_str(json).expect("Failed to parse");
+
+        let Message::SwitchScheduleAction(switch) = message else {
+            panic!("Expected SwitchScheduleAction");
+        };
+        assert_eq!(switch.host_device, "lin0");
+        assert_eq!(switch.schedule_table_name, "DiagSchedule");
+        assert_eq!(switch.at, SwitchAt::EndOfTable);
+        assert!(switch.one_shot);
+    }
+
+    #[test]
+    fn test_deserialize_simulator_faults() {
+        let json = r#"{
+            "action": "start",
+            "bus": {
+                "host_device": "lin0",
+                "plugin": {
+                    "type": "simulator",
+                    "driver": "kvaser",
+                    "host_mode": "master",
+                    "database": "simulator/simulator.ldf",
+                    "schedule_table_name": "DEVMLIN01Schedule01",
+                    "seed": 42,
+                    "faults": [
+                        { "frame_id": 5, "fault": "drop_response", "probability": 0.5 },
+                        { "slot": 2, "fault": { "length": 1 }, "sequence": [false, true] }
+                    ]
+                }
+            }
+        }"#;
+        let message: Message = serde_json::from_str(json).expect("Failed to parse");
+
+        let Message::StartAction(config) = message else {
+            panic!("Expected StartAction");
+        };
+        let Plugin::Simulator(sim) = config.plugin else {
+            panic!("Expected Simulator plugin");
+        };
+        assert_eq!(sim.seed, 42);
+        assert_eq!(sim.clock, ClockMode::Wall);
+        assert_eq!(sim.speed, 1.0);
+        assert_eq!(sim.faults.len(), 2);
+        assert_eq!(sim.faults[0].frame_id, Some(5));
+        assert_eq!(sim.faults[0].fault, Fault::DropResponse);
+        assert_eq!(sim.faults[0].probability, Some(0.5));
+        assert_eq!(sim.faults[1].slot, Some(2));
+        assert_eq!(sim.faults[1].fault, Fault::Length(1));
+        assert_eq!(sim.faults[1].sequence, Some(vec![false, true]));
+    }
+
+    #[test]
+    fn test_reject_fault_probability_out_of_range() {
+        let rule: FaultRule =
+            serde_json::from_str(r#"{ "fault": "drop_response", "probability": 1.0 }"#)
+                .expect("Failed to parse");
+        assert_eq!(rule.probability, Some(1.0));
+
+        for probability in ["-0.1", "1.5"] {
+            let json = format!(r#"{{ "fault": "drop_response", "probability": {probability} }}"#);
+            let err = serde_json::from_str::<FaultRule>(&json).unwrap_err();
+            assert!(err.to_string().contains("not within 0.0 - 1.0"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_reject_non_positive_speed() {
+        for speed in ["0", "-2.0"] {
+            let json = format!(
+                r#"{{
+                    "type": "simulator",
+                    "driver": "kvaser",
+                    "host_mode": "slave",
+                    "database": "simulator/simulator.ldf",
+                    "clock": "virtual",
+                    "speed": {speed}
+                }}"#
+            );
+            let err = serde_json::from_str::<Plugin>(&json).unwrap_err();
+            assert!(err.to_string().contains("not a positive number"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_replay_plugin() {
+        let json = r#"{
+            "host_device": "lin0",
+            "plugin": {
+                "type": "replay",
+                "driver": "kvaser",
+                "trace": "field-issue.asc",
+                "loop": true,
+                "speed": 2.0,
+                "start_offset_ms": 1500
+            }
+        }"#;
+        let config: Config = serde_json::from_str(json).expect("Failed to parse");
+
+        let Plugin::Replay(replay) = config.plugin else {
+            panic!("Expected Replay plugin");
+        };
+        assert_eq!(replay.name, "replay");
+        assert_eq!(replay.trace, "field-issue.asc");
+        assert_eq!(replay.format, None);
+        assert_eq!(replay.device_id, None);
+        assert!(replay.looping);
+        assert_eq!(replay.speed, 2.0);
+        assert_eq!(replay.start_offset_ms, 1500);
+        assert_eq!(replay.end_offset_ms, None);
+    }
+
+    #[test]
+    fn test_deserialize_virtual_plugin() {
+        let json = r#"{
+            "host_device": "lin0",
+            "plugin": {
+                "type": "virtual",
+                "driver": "virtual",
+                "host_mode": "slave",
+                "virtual_bus": "body"
+            }
+        }"#;
+        let config: Config = serde_json::from_str(json).expect("Failed to parse");
+
+        let Plugin::Virtual(virtual_lin) = config.plugin else {
+            panic!("Expected Virtual plugin");
+        };
+        assert_eq!(virtual_lin.name, None);
+        assert_eq!(virtual_lin.host_mode, HostMode::Slave);
+        assert_eq!(virtual_lin.virtual_bus, "body");
+        assert_eq!(virtual_lin.timing, Timing::default());
+    }
+
+    #[test]
+    fn test_virtual_plugin_rejects_base_tick() {
+        let json = r#"{
+            "type": "virtual",
+            "driver": "virtual",
+            "host_mode": "master",
+            "virtual_bus": "body",
+            "base_tick_ms": 10
+        }"#;
+
+        assert!(serde_json::from_str::<Plugin>(json).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_e2e_rules() {
+        let json = r#"[
+            {
+                "frame_id": 3,
+                "counter": { "signal": "SomeCntr", "wrap": 3 },
+                "checksum": { "signal": "SomeChks", "algorithm": "crc8", "bytes": [1] }
+            }
+        ]"#;
+        let rules: Vec<E2eRule> = serde_json::from_str(json).expect("Failed to parse");
+
+        assert_eq!(rules[0].frame_id, 3);
+        assert_eq!(rules[0].counter.as_ref().unwrap().wrap, Some(3));
+
+        let checksum = rules[0].checksum.as_ref().unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Crc8);
+        assert_eq!(checksum.bytes, Some(vec![1]));
+    }
+
+    #[test]
+    fn test_deserialize_wakeup_action() {
+        let json = r#"{"action": "wakeup", "bus": {"host_device": "lin0"}}"#;
+        let message: Message = serde_json::from_str(json).expect("Failed to parse");
+
+        let Message::WakeupAction(target) = message else {
+            panic!("Expected WakeupAction");
+        };
+        assert_eq!(target.host_device, "lin0");
+    }
+
+    #[test]
+    fn test_deserialize_scan_action() {
+        let json = r#"{"action": "scan", "bus": {"device_id": "011121:1", "ldf": true}}"#;
+        let message: Message = serde_json::from_str(json).expect("Failed to parse");
+
+        let Message::ScanAction(scan) = message else {
+            panic!("Expected ScanAction");
+        };
+        assert_eq!(scan.device_id, "011121:1");
+        assert_eq!(scan.baudrate, Baudrate(19_200));
+        assert_eq!(scan.samples, 3);
+        assert!(scan.ldf);
+    }
+
+    #[test]
+    fn test_list_devices_action() {
+        let json = r#"{"action": "list_devices"}"#;
+        let message: Message = serde_json::from_str(json).expect("Failed to parse");
+        assert!(matches!(message, Message::ListDevicesAction));
+
+        let json = serde_json::to_string(&message).expect("Failed to serialize");
+        assert_eq!(json, r#"{"action":"list_devices"}"#);
+    }
+
+    #[test]
+    fn test_auto_baudrate() {
+        let json = r#"{
+            "host_device": "lin0",
+            "baudrate": "auto",
+            "plugin": { "driver": "kvaser", "host_mode": "slave", "device_id": "any-lin" }
+        }"#;
+        let config: Config = serde_json::from_str(json).expect("Failed to parse");
+        assert_eq!(config.baudrate, BusBaudrate::AUTO);
+
+        let json = serde_json::to_string(&config).expect("Failed to serialize");
+        assert!(json.contains(r#""baudrate":"auto""#));
+
+        let json = json.replace(r#""auto""#, r#""fast""#);
+        assert!(serde_json::from_str::<Config>(&json).is_err());
+    }
+
+    #[test]
+    fn test_status_action() {
+        let json = r#"{"action": "status"}"#;
+        let message: Message = serde_json::from_str(json).expect("Failed to parse");
+        assert!(matches!(message, Message::StatusAction));
+    }
+
     #[test]
     fn test_serialize_start_action() {
         let config = Config {
             host_device: "testlin".to_string(),
-            baudrate: Baudrate(9600),
+            baudrate: BusBaudrate::Fixed(Baudrate(9600)),
             plugin: Plugin::Lin(Lin {
                 driver: "kvaser".to_string(),
                 name: None,
                 host_mode: HostMode::Slave,
                 device_id: "1".to_string(),
                 base_tick_ms: BaseTick(5),
+                learn: None,
+                database: None,
+                node: None,
+                idle_timeout_ms: 1000,
+                model: FrameModel::default(),
             }),
         };
 
@@ -271,13 +1151,18 @@ mod tests {
     fn test_serialize_stop_action() {
         let config = Config {
             host_device: "testlin".to_string(),
-            baudrate: Baudrate(9600),
+            baudrate: BusBaudrate::Fixed(Baudrate(9600)),
             plugin: Plugin::Lin(Lin {
                 driver: "kvaser".to_string(),
                 name: None,
                 host_mode: HostMode::Slave,
                 device_id: "1".to_string(),
                 base_tick_ms: BaseTick(5),
+                learn: None,
+                database: None,
+                node: None,
+                idle_timeout_ms: 1000,
+                model: FrameModel::default(),
             }),
         };
 
@@ -1,8 +1,10 @@
 use crate::frame::Frame;
-use crate::masterslave::{FrameReader, Slave};
+use crate::masterslave::{BusEvent, FrameReader, Slave};
+use crate::msg;
 
 use anyhow::Result;
 use std::collections::HashSet;
+use tokio::task::JoinHandle;
 
 pub struct NoEchoSlave<S: Slave> {
     target: S,
@@ -36,6 +38,26 @@ impl<S: Slave> FrameReader for NoEchoSlave<S> {
 
         f
     }
+
+    fn switch_schedule(&mut self, switch: &msg::SwitchSchedule) -> Result<()> {
+        self.target.switch_schedule(switch)
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        self.target.sleep()
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        self.target.wakeup()
+    }
+
+    fn try_read_event(&mut self) -> Option<BusEvent> {
+        self.target.try_read_event()
+    }
+
+    fn close(&mut self) -> Option<JoinHandle<()>> {
+        self.target.close()
+    }
 }
 
 impl<S: Slave> Slave for NoEchoSlave<S> {
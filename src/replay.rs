@@ -0,0 +1,441 @@
+//! Replay of recorded LIN traces with the original inter-frame timing.
+#![allow(clippy::new_ret_no_self)]
+
+use crate::frame::Frame;
+use crate::masterslave::{FrameReader, Slave};
+use crate::msg::{self, TraceFormat};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Frame recorded at time relative to the start of the trace
+#[derive(Debug, PartialEq)]
+pub struct TraceFrame {
+    pub time: Duration,
+    pub frame: Frame,
+}
+
+pub struct Replayer {
+    name: String,
+    frames: Vec<TraceFrame>,
+    span: Duration,
+    speed: f64,
+    looping: bool,
+    index: usize,
+    cycle_start: Duration,
+    started: Option<Instant>,
+}
+
+impl Replayer {
+    pub fn new(config: &msg::Replay) -> Result<Replayer> {
+        log::info!("Replayer::new {} from {}", config.name, config.trace);
+
+        if config.speed <= 0.0 || !config.speed.is_finite() {
+            return Err(anyhow::anyhow!(
+                "Replay speed must be positive, got {}",
+                config.speed
+            ));
+        }
+
+        let format = match config.format {
+            Some(format) => format,
+            None => detect_format(&config.trace)?,
+        };
+
+        let trace = parse_file(&config.trace, format)?;
+
+        let start = Duration::from_millis(config.start_offset_ms);
+        let end = config.end_offset_ms.map(Duration::from_millis);
+
+        let frames: Vec<TraceFrame> = trace
+            .into_iter()
+            .filter(|f| f.time >= start && end.is_none_or(|end| f.time <= end))
+            .map(|f| TraceFrame {
+                time: f.time - start,
+                frame: f.frame,
+            })
+            .collect();
+
+        if frames.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No frames to replay in {} between the given offsets",
+                config.trace
+            ));
+        }
+
+        let span = match end {
+            Some(end) => end.saturating_sub(start),
+            None => loop_span(&frames),
+        };
+
+        log::info!("{} replaying {} frames", config.name, frames.len());
+
+        Ok(Replayer {
+            name: config.name.clone(),
+            frames,
+            // Avoid spinning on a zero length loop
+            span: span.max(Duration::from_millis(1)),
+            speed: config.speed,
+            looping: config.looping,
+            index: 0,
+            cycle_start: Duration::ZERO,
+            started: None,
+        })
+    }
+
+    pub fn new_slave(config: &msg::Replay) -> Result<impl Slave> {
+        Replayer::new(config)
+    }
+
+    /// Next frame due at elapsed (wall) time since the replay started
+    fn poll(&mut self, elapsed: Duration) -> Option<Frame> {
+        let trace_time = elapsed.mul_f64(self.speed);
+
+        if self.index >= self.frames.len() {
+            if !self.looping {
+                return None;
+            }
+
+            self.index = 0;
+            self.cycle_start += self.span;
+
+            log::debug!("{} restarting replay", self.name);
+        }
+
+        let next = &self.frames[self.index];
+
+        if self.cycle_start + next.time <= trace_time {
+            self.index += 1;
+            Some(next.frame.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Length of a cycle without end offset, the time of the last frame plus the median gap between
+/// frames, so the next cycle does not start right on top of the last frame
+fn loop_span(frames: &[TraceFrame]) -> Duration {
+    let mut gaps: Vec<Duration> = frames
+        .windows(2)
+        .map(|pair| pair[1].time.saturating_sub(pair[0].time))
+        .collect();
+    gaps.sort_unstable();
+
+    let gap = gaps.get(gaps.len() / 2).copied().unwrap_or_default();
+
+    frames.last().map_or(Duration::ZERO, |f| f.time) + gap
+}
+
+impl FrameReader for Replayer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn try_read(&mut self) -> Option<Frame> {
+        let elapsed = self.started.get_or_insert_with(Instant::now).elapsed();
+
+        self.poll(elapsed)
+    }
+}
+
+impl Slave for Replayer {
+    fn update(&mut self, _f: &Frame) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        log::info!("Replayer::drop {}", self.name);
+    }
+}
+
+fn detect_format(trace_path: &str) -> Result<TraceFormat> {
+    match Path::new(trace_path).extension().and_then(|e| e.to_str()) {
+        Some("log") => Ok(TraceFormat::Candump),
+        Some("asc") => Ok(TraceFormat::Asc),
+        Some("jsonl") | Some("json") => Ok(TraceFormat::Jsonl),
+        _ => Err(anyhow::anyhow!(
+            "Can't detect trace format of {trace_path}, please specify format"
+        )),
+    }
+}
+
+pub fn parse_file(trace_path: &str, format: TraceFormat) -> Result<Vec<TraceFrame>> {
+    let file = File::open(trace_path).context(format!("Failed to open {trace_path}"))?;
+    let reader = BufReader::new(file);
+
+    let mut lines = reader.lines();
+
+    let mut frames = match format {
+        TraceFormat::Candump => parse_candump_lines(&mut lines)?,
+        TraceFormat::Asc => parse_asc_lines(&mut lines)?,
+        TraceFormat::Jsonl => parse_jsonl_lines(&mut lines)?,
+    };
+
+    // Timestamps are relative to the first recorded frame
+    frames.sort_by_key(|f| f.time);
+    if let Some(first) = frames.first().map(|f| f.time) {
+        frames.iter_mut().for_each(|f| f.time -= first);
+    }
+
+    Ok(frames)
+}
+
+fn parse_candump_lines<I>(lines: &mut I) -> Result<Vec<TraceFrame>>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    let frame_re =
+        Regex::new(r"^\s*\((\d+\.\d+)\)\s+\S+\s+([0-9A-Fa-f]+)#(R\d?|[0-9A-Fa-f]*)\s*$")?;
+
+    let mut frames = Vec::new();
+
+    for line in lines {
+        let line = line?;
+
+        if let Some(m) = frame_re.captures(&line) {
+            let msg = if m[3].starts_with('R') {
+                vec![]
+            } else {
+                parse_hex_bytes(&m[3])?
+            };
+
+            frames.push(TraceFrame {
+                time: Duration::from_secs_f64(m[1].parse()?),
+                frame: Frame::new(u32::from_str_radix(&m[2], 16)?, msg),
+            });
+        } else if !line.trim().is_empty() {
+            log::debug!("Skipping candump line {line}");
+        }
+    }
+
+    Ok(frames)
+}
+
+fn parse_asc_lines<I>(lines: &mut I) -> Result<Vec<TraceFrame>>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    // LIN: "2.031200 Li1 1a Rx 8 00 01 02 03 04 05 06 07 checksum = 0xfe ..."
+    // CAN: "2.031200 1 1a Rx d 8 00 01 02 03 04 05 06 07 ..."
+    let lin_re = Regex::new(
+        r"^\s*(\d+\.\d+)\s+Li?\d*\s+([0-9A-Fa-f]+)\s+(?:Rx|Tx)\s+(\d)((?:\s+[0-9A-Fa-f]{2})*)",
+    )?;
+    let can_re = Regex::new(
+        r"^\s*(\d+\.\d+)\s+\d+\s+([0-9A-Fa-f]+)x?\s+(?:Rx|Tx)\s+d\s+(\d)((?:\s+[0-9A-Fa-f]{2})*)",
+    )?;
+
+    let mut frames = Vec::new();
+    let mut hex_ids = true;
+
+    for line in lines {
+        let line = line?;
+
+        if line.trim_start().starts_with("base ") {
+            hex_ids = line.contains("base hex");
+        } else if let Some(m) = lin_re.captures(&line).or_else(|| can_re.captures(&line)) {
+            let id = if hex_ids {
+                u32::from_str_radix(&m[2], 16)?
+            } else {
+                m[2].parse()?
+            };
+
+            let len: usize = m[3].parse()?;
+            let mut msg = m[4]
+                .split_whitespace()
+                .map(|b| u8::from_str_radix(b, 16))
+                .collect::<Result<Vec<u8>, _>>()?;
+            msg.truncate(len);
+
+            frames.push(TraceFrame {
+                time: Duration::from_secs_f64(m[1].parse()?),
+                frame: Frame::new(id, msg),
+            });
+        }
+    }
+
+    Ok(frames)
+}
+
+#[derive(Deserialize)]
+struct JsonlFrame {
+    /// Time in seconds
+    t: f64,
+    id: u32,
+    #[serde(default)]
+    data: Vec<u8>,
+}
+
+fn parse_jsonl_lines<I>(lines: &mut I) -> Result<Vec<TraceFrame>>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    let mut frames = Vec::new();
+
+    for (n, line) in lines.enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let f: JsonlFrame =
+            serde_json::from_str(&line).context(format!("Invalid trace line {}", n + 1))?;
+
+        frames.push(TraceFrame {
+            time: Duration::from_secs_f64(f.t),
+            frame: Frame::new(f.id, f.data),
+        });
+    }
+
+    Ok(frames)
+}
+
+fn parse_hex_bytes(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("Odd number of hex digits in {hex}"));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn lines(text: &str) -> impl Iterator<Item = io::Result<String>> + '_ {
+        text.lines().map(|l| Ok(l.to_string()))
+    }
+
+    fn trace_frame(ms: u64, id: u32, msg: &[u8]) -> TraceFrame {
+        TraceFrame {
+            time: Duration::from_millis(ms),
+            frame: Frame::new(id, msg.to_vec()),
+        }
+    }
+
+    fn replayer(frames: Vec<TraceFrame>, speed: f64, looping: bool) -> Replayer {
+        Replayer {
+            name: "test".to_string(),
+            span: loop_span(&frames),
+            frames,
+            speed,
+            looping,
+            index: 0,
+            cycle_start: Duration::ZERO,
+            started: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_candump() {
+        let log = "(1436509052.250000) vcan0 011#0102\n\
+                   (1436509052.260000) vcan0 005#\n\
+                   (1436509052.270000) vcan0 010#R\n";
+
+        assert_eq!(
+            parse_candump_lines(&mut lines(log)).unwrap(),
+            vec![
+                TraceFrame {
+                    time: Duration::from_secs_f64(1436509052.25),
+                    frame: Frame::new(0x11, vec![1, 2])
+                },
+                TraceFrame {
+                    time: Duration::from_secs_f64(1436509052.26),
+                    frame: Frame::new(0x05, vec![])
+                },
+                TraceFrame {
+                    time: Duration::from_secs_f64(1436509052.27),
+                    frame: Frame::new(0x10, vec![])
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_asc() {
+        let asc = "date Wed Jun 12 10:00:00 am 2024\n\
+                   base hex  timestamps absolute\n\
+                   Begin Triggerblock Wed Jun 12 10:00:00 am 2024\n\
+                   \x20  0.010000 Li1 1a Rx 2 0a 0b checksum = 0xe9 header time = 40, full time = 70\n\
+                   \x20  0.020000 1  11 Rx d 3 01 02 03 Length = 0 BitCount = 0\n\
+                   End TriggerBlock\n";
+
+        assert_eq!(
+            parse_asc_lines(&mut lines(asc)).unwrap(),
+            vec![
+                trace_frame(10, 0x1a, &[0x0a, 0x0b]),
+                trace_frame(20, 0x11, &[1, 2, 3])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_jsonl() {
+        let jsonl = "{\"t\": 0.5, \"id\": 17, \"data\": [1, 2]}\n\n{\"t\": 0.6, \"id\": 5}\n";
+
+        assert_eq!(
+            parse_jsonl_lines(&mut lines(jsonl)).unwrap(),
+            vec![trace_frame(500, 17, &[1, 2]), trace_frame(600, 5, &[])]
+        );
+    }
+
+    #[test]
+    fn test_replay_keeps_timing_and_speed() {
+        let mut replay = replayer(
+            vec![trace_frame(0, 1, &[1]), trace_frame(20, 2, &[2])],
+            2.0,
+            false,
+        );
+
+        assert_eq!(replay.poll(Duration::ZERO).map(|f| f.id), Some(1));
+        assert_eq!(replay.poll(Duration::from_millis(9)), None);
+        assert_eq!(
+            replay.poll(Duration::from_millis(10)).map(|f| f.id),
+            Some(2)
+        );
+        assert_eq!(replay.poll(Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn test_replay_loops() {
+        let mut replay = replayer(
+            vec![trace_frame(0, 1, &[1]), trace_frame(20, 2, &[2])],
+            1.0,
+            true,
+        );
+
+        let ids: Vec<(u64, u32)> = (0..=80)
+            .step_by(10)
+            .filter_map(|ms| replay.poll(Duration::from_millis(ms)).map(|f| (ms, f.id)))
+            .collect();
+
+        // A cycle lasts a gap longer than the last frame
+        assert_eq!(ids, vec![(0, 1), (20, 2), (40, 1), (60, 2), (80, 1)]);
+    }
+
+    #[test]
+    fn test_loop_span_adds_median_gap() {
+        let frames = vec![
+            trace_frame(0, 1, &[1]),
+            trace_frame(10, 2, &[2]),
+            trace_frame(20, 3, &[3]),
+            trace_frame(100, 4, &[4]),
+        ];
+
+        assert_eq!(loop_span(&frames), Duration::from_millis(110));
+        assert_eq!(loop_span(&frames[..1]), Duration::ZERO);
+    }
+}
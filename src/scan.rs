@@ -0,0 +1,299 @@
+//! Discovery scan of an undocumented LIN bus.
+//!
+//! The scan sends a header for every unconditional frame id and records which ids are answered,
+//! with the response length, the checksum model and the payloads seen.
+use crate::frame;
+use crate::kvaser;
+use crate::learn;
+use crate::msg;
+use crate::timing::FrameTiming;
+use anyhow::Result;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Highest unconditional frame id, the ids above are reserved for diagnostics
+pub const MAX_FRAME_ID: u32 = 0x3b;
+
+const SCHEDULE_TABLE_NAME: &str = "ScanSchedule";
+const SCHEDULE_DELAY_MS: u32 = 10;
+
+/// Slack on top of the nominal frame time, covering slow responders and driver latency
+const RESPONSE_MARGIN: Duration = Duration::from_millis(20);
+
+pub trait Probe {
+    /// Send the header for id and wait up to timeout for a response, returning its data and checksum
+    fn probe(&mut self, id: u32, timeout: Duration) -> Result<Option<(Vec<u8>, u8)>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumType {
+    Classic,
+    Enhanced,
+    /// Neither model matches, the response may be disturbed
+    Invalid,
+}
+
+impl ChecksumType {
+    pub fn detect(id: u32, data: &[u8], checksum: u8) -> Self {
+        if checksum == frame::enhanced_checksum(id, data) {
+            ChecksumType::Enhanced
+        } else if checksum == frame::classic_checksum(data) {
+            ChecksumType::Classic
+        } else {
+            ChecksumType::Invalid
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScannedFrame {
+    pub id: u32,
+    /// Length of the first response
+    pub length: usize,
+    pub checksum: ChecksumType,
+    /// Distinct payloads in the order they were first seen
+    pub samples: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanReport {
+    pub baudrate: u32,
+    pub frames: Vec<ScannedFrame>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ldf: Option<String>,
+}
+
+/// Scan a Kvaser channel as LIN master
+pub fn run(scan: &msg::Scan) -> Result<ScanReport> {
+    let baudrate = u32::from(scan.baudrate);
+    let mut probe = kvaser::KvaserLinux::new_scanner("scan", &scan.device_id, baudrate)?;
+
+    let mut report = self::scan(&mut probe, baudrate, scan.samples)?;
+    if scan.ldf {
+        report.ldf = Some(skeleton_ldf(&report));
+    }
+
+    Ok(report)
+}
+
+/// Request every frame id samples times, ids not answered the first time are not retried
+pub fn scan(probe: &mut impl Probe, baudrate: u32, samples: usize) -> Result<ScanReport> {
+    let timing = FrameTiming::new(baudrate, &msg::Timing::default());
+    let timeout = timing.frame(8) * 14 / 10 + RESPONSE_MARGIN;
+
+    let mut frames = Vec::new();
+
+    for id in 0..=MAX_FRAME_ID {
+        let mut scanned: Option<ScannedFrame> = None;
+
+        for _ in 0..samples {
+            let Some((data, checksum)) = probe.probe(id, timeout)? else {
+                break;
+            };
+
+            let scanned = scanned.get_or_insert_with(|| ScannedFrame {
+                id,
+                length: data.len(),
+                checksum: ChecksumType::detect(id, &data, checksum),
+                samples: Vec::new(),
+            });
+
+            if !scanned.samples.contains(&data) {
+                scanned.samples.push(data);
+            }
+        }
+
+        if let Some(scanned) = scanned {
+            log::info!(
+                "Frame {id:#x} answered with {} bytes, {:?} checksum",
+                scanned.length,
+                scanned.checksum
+            );
+            frames.push(scanned);
+        }
+    }
+
+    Ok(ScanReport {
+        baudrate,
+        frames,
+        ldf: None,
+    })
+}
+
+/// LDF with one frame per answered id published by a single slave, and a byte signal per data byte.
+/// Classic checksums make it a LIN 1.3 description, the checksum seen is noted on every frame
+pub fn skeleton_ldf(report: &ScanReport) -> String {
+    let schedule: Vec<(u32, u32)> = report
+        .frames
+        .iter()
+        .map(|f| (f.id, SCHEDULE_DELAY_MS))
+        .collect();
+
+    let mut ldf = learn::skeleton(
+        report.baudrate,
+        SCHEDULE_DELAY_MS,
+        learn::SLAVE_NAME,
+        report
+            .frames
+            .iter()
+            .map(|f| (f.id, f.samples[0].as_slice(), f.length)),
+        SCHEDULE_TABLE_NAME,
+        &schedule,
+    );
+
+    if report
+        .frames
+        .iter()
+        .any(|f| f.checksum == ChecksumType::Classic)
+    {
+        ldf.header.protocol_version = "1.3".to_string();
+    }
+
+    for scanned in &report.frames {
+        if let Some(frame) = ldf.frames.values_mut().find(|f| f.id == scanned.id) {
+            frame.comment = Some(format!("{:?} checksum", scanned.checksum));
+        }
+    }
+
+    ldf.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ldf;
+    use std::collections::HashMap;
+
+    use pretty_assertions::assert_eq;
+
+    // Answers from a list of payloads per id, repeating the last one
+    struct FakeBus {
+        responses: HashMap<u32, Vec<(Vec<u8>, u8)>>,
+        requests: Vec<u32>,
+    }
+
+    impl Probe for FakeBus {
+        fn probe(&mut self, id: u32, _timeout: Duration) -> Result<Option<(Vec<u8>, u8)>> {
+            self.requests.push(id);
+
+            let Some(responses) = self.responses.get_mut(&id) else {
+                return Ok(None);
+            };
+
+            Ok(if responses.len() > 1 {
+                Some(responses.remove(0))
+            } else {
+                responses.first().cloned()
+            })
+        }
+    }
+
+    fn enhanced(id: u32, data: &[u8]) -> (Vec<u8>, u8) {
+        (data.to_vec(), frame::enhanced_checksum(id, data))
+    }
+
+    fn classic(data: &[u8]) -> (Vec<u8>, u8) {
+        (data.to_vec(), frame::classic_checksum(data))
+    }
+
+    #[test]
+    fn test_scan_records_responses() {
+        let mut bus = FakeBus {
+            responses: HashMap::from([
+                (0x10, vec![enhanced(0x10, &[1, 2]), enhanced(0x10, &[1, 3])]),
+                (0x21, vec![classic(&[7])]),
+                (0x22, vec![(vec![1], 0)]),
+            ]),
+            requests: Vec::new(),
+        };
+
+        let report = scan(&mut bus, 19_200, 3).unwrap();
+
+        assert_eq!(
+            report.frames,
+            vec![
+                ScannedFrame {
+                    id: 0x10,
+                    length: 2,
+                    checksum: ChecksumType::Enhanced,
+                    samples: vec![vec![1, 2], vec![1, 3]],
+                },
+                ScannedFrame {
+                    id: 0x21,
+                    length: 1,
+                    checksum: ChecksumType::Classic,
+                    samples: vec![vec![7]],
+                },
+                ScannedFrame {
+                    id: 0x22,
+                    length: 1,
+                    checksum: ChecksumType::Invalid,
+                    samples: vec![vec![1]],
+                },
+            ]
+        );
+
+        // Silent ids are requested once, answered ids once per sample
+        assert_eq!(bus.requests.len(), 57 + 3 * 3);
+        assert_eq!(bus.requests.iter().max(), Some(&MAX_FRAME_ID));
+    }
+
+    #[test]
+    fn test_skeleton_ldf_parses() {
+        let report = ScanReport {
+            baudrate: 19_200,
+            frames: vec![ScannedFrame {
+                id: 0x10,
+                length: 2,
+                checksum: ChecksumType::Enhanced,
+                samples: vec![vec![1, 2]],
+            }],
+            ldf: None,
+        };
+
+        let ldf = ldf::parse_str(&skeleton_ldf(&report)).unwrap();
+
+        assert_eq!(ldf.header.baudrate, 19_200);
+        assert_eq!(ldf.nodes.master, "Master");
+
+        let frame = ldf.frames.get("Frame10").unwrap();
+        assert_eq!((frame.id, frame.size), (0x10, 2));
+        assert_eq!(frame.signals[1].name, "Frame10Byte1");
+        assert_eq!(frame.signals[1].offset, 8);
+
+        let signal = ldf.signals.get("Frame10Byte1").unwrap();
+        assert_eq!(signal.init_value, ldf::InitValue::Scalar(2));
+        assert_eq!(ldf.schedule_tables["ScanSchedule"].items.len(), 1);
+    }
+
+    #[test]
+    fn test_classic_checksum_skeleton_is_lin_1_3() {
+        let report = ScanReport {
+            baudrate: 19_200,
+            frames: vec![
+                ScannedFrame {
+                    id: 0x10,
+                    length: 2,
+                    checksum: ChecksumType::Enhanced,
+                    samples: vec![vec![1, 2]],
+                },
+                ScannedFrame {
+                    id: 0x21,
+                    length: 1,
+                    checksum: ChecksumType::Classic,
+                    samples: vec![vec![7]],
+                },
+            ],
+            ldf: None,
+        };
+
+        let text = skeleton_ldf(&report);
+        assert!(text.contains("\t// Classic checksum\n\tFrame21: 0x21, Slave, 1 {"));
+        assert!(text.contains("\t// Enhanced checksum\n\tFrame10: 0x10, Slave, 2 {"));
+
+        let ldf = ldf::parse_str(&text).unwrap();
+        assert_eq!(ldf.header.protocol_version, "1.3");
+        assert_eq!(ldf.frames.len(), 2);
+    }
+}
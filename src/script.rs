@@ -0,0 +1,163 @@
+//! Rhai scripts backing simulated nodes.
+//!
+//! A script may define the hooks
+//! - `on_frame_received(id, signals)`, called with the decoded signals of each frame received from the vbus
+//! - `on_slot(id)`, called before a frame is emitted, returning a map of signal values to send or `()`
+//!
+//! State is kept in `this`, an object map preserved between hook calls.
+use crate::ldf;
+use anyhow::Result;
+use rhai::{AST, CallFnOptions, Dynamic, Engine, INT, Map, Scope};
+
+const ON_FRAME_RECEIVED: &str = "on_frame_received";
+const ON_SLOT: &str = "on_slot";
+
+/// Bound the work of a single hook call, a runaway script must not stall the bus
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    state: Dynamic,
+    has_on_frame_received: bool,
+    has_on_slot: bool,
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|s| log::info!("script: {s}"));
+
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| anyhow::anyhow!("Failed to load script {path} - {e}"))?;
+
+        // Top level statements run once when the script is loaded
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| anyhow::anyhow!("Failed to run script {path} - {e}"))?;
+
+        let has_fn = |name: &str, params: usize| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == params)
+        };
+
+        Ok(Script {
+            has_on_frame_received: has_fn(ON_FRAME_RECEIVED, 2),
+            has_on_slot: has_fn(ON_SLOT, 1),
+            engine,
+            ast,
+            scope,
+            state: Dynamic::from_map(Map::new()),
+        })
+    }
+
+    /// Pass a frame received from the vbus to the script
+    pub fn on_frame_received(&mut self, ldf: &ldf::LDF, frame: &ldf::Frame, msg: &[u8]) {
+        if !self.has_on_frame_received {
+            return;
+        }
+
+        let signals: Map = frame
+            .signals
+            .iter()
+            .filter_map(|frame_signal| {
+                let signal = ldf.signals.get(&frame_signal.name)?;
+                let value = ldf::decode_signal(msg, frame_signal.offset, signal.size);
+
+                Some((
+                    frame_signal.name.as_str().into(),
+                    Dynamic::from_int(value as INT),
+                ))
+            })
+            .collect();
+
+        if let Err(err) = self.call(ON_FRAME_RECEIVED, (frame.id as INT, signals)) {
+            log::error!(
+                "Script {ON_FRAME_RECEIVED} failed for frame {:#x} - {err}",
+                frame.id
+            );
+        }
+    }
+
+    /// Let the script set signals of a frame about to be emitted
+    pub fn on_slot(&mut self, ldf: &ldf::LDF, frame: &ldf::Frame, msg: &mut [u8]) {
+        if !self.has_on_slot {
+            return;
+        }
+
+        let result = match self.call(ON_SLOT, (frame.id as INT,)) {
+            Ok(result) => result,
+            Err(err) => {
+                log::error!("Script {ON_SLOT} failed for frame {:#x} - {err}", frame.id);
+                return;
+            }
+        };
+
+        let Some(signals) = result.try_cast::<Map>() else {
+            return;
+        };
+
+        for (name, value) in signals {
+            let position = frame
+                .signals
+                .iter()
+                .find(|s| s.name == name.as_str())
+                .and_then(|s| Some((s.offset, ldf.signals.get(&s.name)?.size)));
+
+            match (position, value.as_int()) {
+                (Some((offset, size)), Ok(value)) => {
+                    ldf::encode_signal(msg, offset, size, value as u64)
+                }
+                (None, _) => log::warn!("Script set unknown signal {name} in frame {}", frame.name),
+                (_, Err(type_name)) => {
+                    log::warn!("Script set signal {name} to a {type_name}, expected an integer")
+                }
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str, args: impl rhai::FuncArgs) -> Result<Dynamic> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+
+        self.engine
+            .call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, name, args)
+            .map_err(|e| anyhow::anyhow!("{e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_script_answers_request() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let request = ldf.frames.get("DEVMLIN01Fr01").unwrap();
+        let response = ldf.frames.get("DEVS1LIN01Fr1").unwrap();
+        let mut script = Script::load("src/testdata/respond.rhai").unwrap();
+
+        let mut msg = vec![0, 0, 0];
+        script.on_slot(&ldf, response, &mut msg);
+        assert_eq!(msg, vec![0, 0, 0]);
+
+        // SomeReq1 = 2 at bit offset 6
+        script.on_frame_received(&ldf, request, &[0b1000_0000, 0]);
+
+        // SomeQf = 1 at bit offset 16
+        script.on_slot(&ldf, response, &mut msg);
+        assert_eq!(msg, vec![0, 0, 0b01]);
+    }
+
+    #[test]
+    fn test_missing_script_fails_to_load() {
+        assert!(Script::load("src/testdata/no-such-script.rhai").is_err());
+    }
+}
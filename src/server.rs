@@ -3,17 +3,24 @@
 use anyhow::Result;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task;
 use tokio::time::{self, Duration};
 use tokio_util::sync::CancellationToken;
 
+use crate::health::Health;
+use crate::kvaser;
 use crate::msg;
+use crate::scan;
 use crate::worker;
 
+/// Longest a stopping bus may take, long enough for its Kvaser channel to close
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Debug)]
 struct TaskResult {
     id: String,
@@ -22,7 +29,32 @@ struct TaskResult {
 
 struct Task {
     handle: task::JoinHandle<Result<()>>,
+    /// Kvaser device id of the channel used by the bus, if any
+    device_id: Option<String>,
     cancel_tx: oneshot::Sender<()>,
+    command_tx: mpsc::Sender<worker::Request>,
+    /// Health of the bus, only supervised for hardware interfaces
+    health_rx: watch::Receiver<Option<Health>>,
+    /// Baudrate of the bus, detected on the bus if configured "auto", set once the worker is ready
+    baudrate: u32,
+}
+
+/// Device ids of the channels being scanned, shared with the detached scans
+type Scans = Arc<Mutex<HashSet<String>>>;
+
+/// Result of the start action
+#[derive(Debug, Serialize)]
+struct Started {
+    baudrate: u32,
+}
+
+/// State of a running bus reported by the status action
+#[derive(Debug, Serialize)]
+struct BusStatus {
+    host_device: String,
+    baudrate: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<Health>,
 }
 
 pub async fn serve(socket_path: &str, shutdown: CancellationToken) -> Result<()> {
@@ -31,6 +63,7 @@ pub async fn serve(socket_path: &str, shutdown: CancellationToken) -> Result<()>
     let _ = std::fs::remove_file(socket_path);
 
     let mut tasks = HashMap::<String, Task>::new();
+    let scans = Scans::default();
 
     let listener = UnixListener::bind(socket_path)?;
 
@@ -57,7 +90,7 @@ pub async fn serve(socket_path: &str, shutdown: CancellationToken) -> Result<()>
 
         match read_json_from_socket::<msg::Message>(&mut sock).await {
                     Ok(action) => {
-            handle_msg(action, &mut sock, &mut tasks, exit_tx.clone()).await;
+            handle_msg(action, sock, &mut tasks, &scans, exit_tx.clone()).await;
                     }
 
                     Err(e) => {
@@ -76,40 +109,92 @@ pub async fn serve(socket_path: &str, shutdown: CancellationToken) -> Result<()>
 
 async fn handle_msg(
     msg: msg::Message,
-    sock: &mut UnixStream,
+    mut sock: UnixStream,
     tasks: &mut HashMap<String, Task>,
+    scans: &Scans,
     exit_tx: mpsc::Sender<TaskResult>,
 ) {
     log::debug!("Received new message: {:?}", msg);
 
     match msg {
         msg::Message::StartAction(config) => {
-            handle_start_action(config, sock, tasks, exit_tx).await;
+            handle_start_action(config, &mut sock, tasks, scans, exit_tx).await;
         }
         msg::Message::StopAction(config) => {
-            handle_stop_action(config, sock, tasks).await;
+            handle_stop_action(config, &mut sock, tasks).await;
+        }
+        msg::Message::SwitchScheduleAction(switch) => {
+            let id = switch.host_device.clone();
+            handle_command(
+                &id,
+                worker::Command::SwitchSchedule(switch),
+                &mut sock,
+                tasks,
+            )
+            .await;
+        }
+        msg::Message::SleepAction(target) => {
+            handle_command(
+                &target.host_device,
+                worker::Command::Sleep,
+                &mut sock,
+                tasks,
+            )
+            .await;
+        }
+        msg::Message::WakeupAction(target) => {
+            handle_command(
+                &target.host_device,
+                worker::Command::Wakeup,
+                &mut sock,
+                tasks,
+            )
+            .await;
+        }
+        msg::Message::ScanAction(scan) => {
+            handle_scan_action(scan, sock, tasks, scans).await;
+        }
+        msg::Message::ListDevicesAction => {
+            handle_list_devices_action(&mut sock, tasks).await;
+        }
+        msg::Message::StatusAction => {
+            handle_status_action(&mut sock, tasks).await;
         }
     }
 }
 
 async fn handle_start_action(
-    config: msg::Config,
+    mut config: msg::Config,
     sock: &mut UnixStream,
     tasks: &mut HashMap<String, Task>,
+    scans: &Scans,
     exit_tx: mpsc::Sender<TaskResult>,
 ) {
+    if let Some(device_id) = config.plugin.device_id_mut() {
+        match resolve_device(device_id, tasks, scans).await {
+            Ok(resolved) => *device_id = resolved,
+            Err(e) => {
+                let _ = write_json_to_socket(sock, &msg::Response::err(e)).await;
+                return;
+            }
+        }
+    }
+
     let id: String = config.host_device.clone();
 
     let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
     let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+    let (command_tx, command_rx) = mpsc::channel(16);
+    let (health_tx, health_rx) = watch::channel(None);
 
-    let task = Task {
+    let mut task = Task {
+        device_id: config.plugin.device_id().map(str::to_string),
         handle: tokio::spawn(async move {
             let id = config.host_device.clone();
 
             log::info!("Launched task for {id}");
 
-            let res = worker::run(&id, config, cancel_rx, ready_tx).await;
+            let res = worker::run(&id, config, cancel_rx, command_rx, ready_tx, health_tx).await;
 
             log::log!(
                 if res.is_ok() {
@@ -127,6 +212,9 @@ async fn handle_start_action(
             Ok(())
         }),
         cancel_tx,
+        command_tx,
+        health_rx,
+        baudrate: 0,
     };
 
     // Looks a bit weird, but the wrapping here is
@@ -134,9 +222,10 @@ async fn handle_start_action(
     //   |-> ready_rx receives a value (Err if closed without a value, Ok otherwise)
     //     |-> the value returned by ready_rx (Ok() if success, Err(error message) otherwise)
     let response = match time::timeout(Duration::from_secs(5), ready_rx).await {
-        Ok(Ok(Ok(()))) => {
+        Ok(Ok(Ok(baudrate))) => {
+            task.baudrate = baudrate;
             tasks.insert(id, task);
-            msg::Response::ok()
+            msg::Response::ok_with(Started { baudrate })
         }
         Ok(Ok(Err(e))) => {
             task.handle.abort();
@@ -155,6 +244,35 @@ async fn handle_start_action(
     let _ = write_json_to_socket(sock, &response).await;
 }
 
+/// Device id of the channel a selector addresses, channels of running buses and scans are not
+/// free and a channel being scanned is rejected
+async fn resolve_device(
+    selector: &str,
+    tasks: &HashMap<String, Task>,
+    scans: &Scans,
+) -> Result<String> {
+    let scanned: Vec<String> = scans.lock().unwrap().iter().cloned().collect();
+    let in_use: Vec<String> = tasks
+        .values()
+        .filter_map(|task| task.device_id.clone())
+        .chain(scanned)
+        .collect();
+
+    let selector = selector.to_string();
+
+    // Initializing the Kvaser library may block
+    let device = task::spawn_blocking(move || kvaser::find_device(&selector, &in_use)).await??;
+
+    if scans.lock().unwrap().contains(&device.device_id) {
+        return Err(anyhow::anyhow!(
+            "device {} is being scanned",
+            device.device_id
+        ));
+    }
+
+    Ok(device.device_id)
+}
+
 async fn handle_stop_action(
     config: msg::Config,
     sock: &mut UnixStream,
@@ -176,10 +294,10 @@ async fn handle_stop_action(
                 }
             }
 
-            _ = time::sleep(Duration::from_secs(1)) => {
+            _ = time::sleep(STOP_TIMEOUT) => {
                 task.handle.abort();
                 let _ = task.handle.await;
-                log::warn!("Task aborted after 1s");
+                log::warn!("Task aborted after {STOP_TIMEOUT:?}");
             }
         }
 
@@ -192,6 +310,118 @@ async fn handle_stop_action(
     let _ = write_json_to_socket(sock, &response).await;
 }
 
+async fn handle_command(
+    id: &str,
+    command: worker::Command,
+    sock: &mut UnixStream,
+    tasks: &mut HashMap<String, Task>,
+) {
+    let response = if let Some(task) = tasks.get(id) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        match task
+            .command_tx
+            .send(worker::Request { command, reply_tx })
+            .await
+        {
+            Ok(()) => match time::timeout(Duration::from_secs(1), reply_rx).await {
+                Ok(Ok(Ok(()))) => msg::Response::ok(),
+                Ok(Ok(Err(e))) => msg::Response::err(e),
+                Ok(Err(_)) => msg::Response::err("worker exited before replying"),
+                Err(_) => msg::Response::err("timed out waiting for worker reply"),
+            },
+            Err(_) => msg::Response::err(format!("task for {id} is not accepting commands")),
+        }
+    } else {
+        log::warn!("Task {id} is not running");
+        msg::Response::err(format!("no running task for {id}"))
+    };
+
+    let _ = write_json_to_socket(sock, &response).await;
+}
+
+async fn handle_scan_action(
+    mut scan: msg::Scan,
+    mut sock: UnixStream,
+    tasks: &HashMap<String, Task>,
+    scans: &Scans,
+) {
+    scan.device_id = match resolve_device(&scan.device_id, tasks, scans).await {
+        Ok(device_id) => device_id,
+        Err(e) => {
+            let _ = write_json_to_socket(&mut sock, &msg::Response::err(e)).await;
+            return;
+        }
+    };
+
+    if let Some((id, _)) = tasks
+        .iter()
+        .find(|(_, task)| task.device_id.as_ref() == Some(&scan.device_id))
+    {
+        log::warn!("Device {} is used by {id}", scan.device_id);
+        let response = msg::Response::err(format!("device {} is used by {id}", scan.device_id));
+        let _ = write_json_to_socket(&mut sock, &response).await;
+        return;
+    }
+
+    log::info!("Scanning device {}", scan.device_id);
+
+    scans.lock().unwrap().insert(scan.device_id.clone());
+    let scans = scans.clone();
+
+    // A scan takes seconds, it runs detached and replies on the socket once done so the server
+    // keeps serving meanwhile
+    tokio::spawn(async move {
+        let device_id = scan.device_id.clone();
+
+        // The Kvaser channel is opened and scanned on a blocking thread
+        let response = match task::spawn_blocking(move || scan::run(&scan)).await {
+            Ok(Ok(report)) => msg::Response::ok_with(report),
+            Ok(Err(e)) => msg::Response::err(e),
+            Err(join) => msg::Response::err(format!("scan failed: {join}")),
+        };
+
+        // Freed before replying, so the device can be started as soon as the reply is read
+        scans.lock().unwrap().remove(&device_id);
+
+        let _ = write_json_to_socket(&mut sock, &response).await;
+    });
+}
+
+async fn handle_list_devices_action(sock: &mut UnixStream, tasks: &HashMap<String, Task>) {
+    // Initializing the Kvaser library may block
+    let response = match task::spawn_blocking(kvaser::list_devices).await {
+        Ok(Ok(mut devices)) => {
+            for device in &mut devices {
+                device.used_by = tasks
+                    .iter()
+                    .find(|(_, task)| task.device_id.as_ref() == Some(&device.device_id))
+                    .map(|(id, _)| id.clone());
+            }
+
+            msg::Response::ok_with(devices)
+        }
+        Ok(Err(e)) => msg::Response::err(e),
+        Err(join) => msg::Response::err(format!("listing devices failed: {join}")),
+    };
+
+    let _ = write_json_to_socket(sock, &response).await;
+}
+
+async fn handle_status_action(sock: &mut UnixStream, tasks: &HashMap<String, Task>) {
+    let mut statuses: Vec<BusStatus> = tasks
+        .iter()
+        .map(|(id, task)| BusStatus {
+            host_device: id.clone(),
+            baudrate: task.baudrate,
+            health: *task.health_rx.borrow(),
+        })
+        .collect();
+    statuses.sort_by(|a, b| a.host_device.cmp(&b.host_device));
+
+    let _ = write_json_to_socket(sock, &msg::Response::ok_with(statuses)).await;
+}
+
 async fn write_json_to_socket<T: Serialize>(socket: &mut UnixStream, value: &T) -> Result<()> {
     let mut bytes = serde_json::to_vec(value)?;
     bytes.push(b'\n');
@@ -1,75 +1,463 @@
 #![allow(clippy::new_ret_no_self)]
 
+use crate::clock::{Clock, VirtualClock};
+use crate::diag::DiagResponder;
+use crate::e2e::E2eProtector;
+use crate::fault::FaultInjector;
 use crate::frame;
 use crate::ldf;
 use crate::masterslave::{FrameReader, Master, Slave};
+use crate::msg;
+use crate::script::Script;
+use crate::timing::FrameTiming;
 
 use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Time from a wake-up pulse until the master resumes sending headers
+const WAKEUP_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusState {
+    Awake,
+    Sleeping,
+}
 
 pub struct MasterSimulator {
     name: String,
     schedule_table_name: String,
     ldf: ldf::LDF,
     table_index: i32,
-    elapsed_in_table_index: u32,
+    slot_start: Duration,
+    slot_sent: bool,
+    clock: Arc<dyn Clock>,
+    pending_switch: Option<msg::SwitchSchedule>,
+    resume: Option<Resume>,
+    faults: FaultInjector,
+    e2e: E2eProtector,
+    script: Option<Script>,
+    bus_state: BusState,
+    pending_sleep: bool,
+    outbox: VecDeque<frame::Frame>,
+    timing: FrameTiming,
+    // Frame on the wire and the time its transmission ends
+    in_flight: Option<(Duration, frame::Frame)>,
+}
+
+/// What a simulated node does to its frames beyond the LDF, and the time it runs on
+pub struct Options {
+    pub faults: FaultInjector,
+    pub e2e: E2eProtector,
+    pub script: Option<Script>,
+    pub timing: FrameTiming,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Table and slot to return to when a one-shot table has run once
+struct Resume {
+    schedule_table_name: String,
+    table_index: i32,
 }
 
 impl MasterSimulator {
-    pub fn new(name: &str, ldf: ldf::LDF, schedule_table_name: &str) -> Result<impl Slave> {
+    pub fn new(
+        name: &str,
+        ldf: ldf::LDF,
+        schedule_table_name: &str,
+        options: Options,
+    ) -> Result<impl Slave> {
         log::info!("MasterSimulator::new {name}");
 
+        let Options {
+            faults,
+            e2e,
+            script,
+            timing,
+            clock,
+        } = options;
+
         Ok(MasterSimulator {
             name: name.to_string(),
             schedule_table_name: schedule_table_name.to_string(),
             ldf,
             table_index: 0,
-            elapsed_in_table_index: 0,
+            slot_start: clock.now(),
+            slot_sent: false,
+            clock,
+            pending_switch: None,
+            resume: None,
+            faults,
+            e2e,
+            script,
+            bus_state: BusState::Awake,
+            pending_sleep: false,
+            outbox: VecDeque::new(),
+            timing,
+            in_flight: None,
         })
     }
 
     fn try_read(&mut self) -> Option<frame::Frame> {
-        if let Some(table) = self.ldf.schedule_tables.get(&self.schedule_table_name) {
-            let table_entry = &table.items[self.table_index as usize];
+        if let Some(frame) = self.outbox.pop_front() {
+            return Some(frame);
+        }
 
-            if let Some(frame) = self.ldf.frames.get(&table_entry.name) {
-                let elapsed_in_table_index = self.elapsed_in_table_index;
+        let now = self.clock.now();
 
-                self.elapsed_in_table_index += self.ldf.nodes.base_tick_ms;
+        // A frame is visible once it has been completely transmitted
+        if let Some((end, _)) = &self.in_flight {
+            if now < *end {
+                return None;
+            }
 
-                if self.elapsed_in_table_index >= table_entry.delay as u32 {
-                    self.table_index = (self.table_index + 1) % table.items.len() as i32;
-                    self.elapsed_in_table_index = 0;
-                }
+            return self.in_flight.take().map(|(_, frame)| frame);
+        }
 
-                if elapsed_in_table_index == 0 {
-                    let msg = if frame.owner == self.ldf.nodes.master {
-                        (0..frame.size).collect()
-                    } else {
-                        vec![]
-                    };
+        // The schedule is paused while the bus sleeps
+        if self.bus_state == BusState::Sleeping {
+            return None;
+        }
 
-                    return Some(frame::Frame { id: frame.id, msg });
-                }
+        // Move on from an ended slot, at most one slot per read so no frame is skipped
+        if self.slot_sent
+            && let Some((delay, table_len)) = self.current_slot_delay()
+            && now >= self.slot_start + delay
+        {
+            self.slot_start += delay;
+            self.slot_sent = false;
+            self.table_index = (self.table_index + 1) % table_len as i32;
+            self.end_of_slot(self.table_index == 0);
+
+            if self.pending_sleep {
+                log::info!("{} sending go-to-sleep command", self.name);
+
+                self.pending_sleep = false;
+                self.bus_state = BusState::Sleeping;
+                return self.transmit(now, frame::Frame::go_to_sleep());
+            }
+        }
+
+        if self.slot_sent || now < self.slot_start {
+            return None;
+        }
+
+        let table = self.ldf.schedule_tables.get(&self.schedule_table_name)?;
+        let slot = self.table_index as usize;
+        let table_entry = &table.items[slot];
+
+        // Frames missing in the LDF leave the slot silent
+        self.slot_sent = true;
+        let frame = self.ldf.frames.get(&table_entry.name)?;
+
+        let mut lin_frame = frame::Frame::new(frame.id, vec![]);
+
+        if frame.owner == self.ldf.nodes.master {
+            lin_frame.msg = (0..frame.size).collect();
+
+            if let Some(script) = self.script.as_mut() {
+                script.on_slot(&self.ldf, frame, &mut lin_frame.msg);
             }
+
+            self.e2e.protect(frame.id, &mut lin_frame.msg);
         }
 
+        let lin_frame = self
+            .faults
+            .inject(&self.ldf, Some(frame), Some(slot), lin_frame)?;
+
+        self.transmit(now, lin_frame)
+    }
+
+    // Start transmitting frame at the start of the current slot
+    fn transmit(&mut self, now: Duration, frame: frame::Frame) -> Option<frame::Frame> {
+        let end = self.slot_start + self.timing.frame(frame.msg.len());
+
+        if now >= end {
+            return Some(frame);
+        }
+
+        self.in_flight = Some((end, frame));
         None
     }
+
+    fn current_slot_delay(&self) -> Option<(Duration, usize)> {
+        let table = self.ldf.schedule_tables.get(&self.schedule_table_name)?;
+        let table_entry = table.items.get(self.table_index as usize)?;
+
+        Some((
+            Duration::from_micros((table_entry.delay * 1000.0) as u64),
+            table.items.len(),
+        ))
+    }
+
+    fn switch_schedule(&mut self, switch: &msg::SwitchSchedule) -> Result<()> {
+        match self.ldf.schedule_tables.get(&switch.schedule_table_name) {
+            Some(table) if !table.items.is_empty() => {
+                log::info!(
+                    "{} switching to schedule table {} at {:?} (one_shot={})",
+                    self.name,
+                    switch.schedule_table_name,
+                    switch.at,
+                    switch.one_shot
+                );
+
+                self.pending_switch = Some(switch.clone());
+                Ok(())
+            }
+            Some(_) => Err(anyhow::anyhow!(
+                "Schedule table {} is empty",
+                switch.schedule_table_name
+            )),
+            None => Err(anyhow::anyhow!(
+                "Schedule table {} not found",
+                switch.schedule_table_name
+            )),
+        }
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        if self.bus_state == BusState::Sleeping {
+            return Err(anyhow::anyhow!("{} bus is already sleeping", self.name));
+        }
+
+        // The go-to-sleep command is sent once the current slot has ended
+        self.pending_sleep = true;
+        Ok(())
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        if self.bus_state == BusState::Awake {
+            return Err(anyhow::anyhow!("{} bus is not sleeping", self.name));
+        }
+
+        self.outbox.push_back(frame::Frame::wakeup());
+        self.wake();
+        Ok(())
+    }
+
+    // Restart the current schedule table once the wake-up delay has passed
+    fn wake(&mut self) {
+        log::info!("{} bus woken up", self.name);
+
+        self.bus_state = BusState::Awake;
+        self.table_index = 0;
+        self.slot_sent = false;
+        self.slot_start = self.clock.now() + WAKEUP_DELAY;
+    }
+
+    // Called when a slot has ended, table_index already points at the next slot
+    fn end_of_slot(&mut self, end_of_table: bool) {
+        if end_of_table && let Some(resume) = self.resume.take() {
+            log::debug!(
+                "{} one-shot table {} done, resuming {}",
+                self.name,
+                self.schedule_table_name,
+                resume.schedule_table_name
+            );
+
+            self.schedule_table_name = resume.schedule_table_name;
+            self.table_index = resume.table_index;
+        }
+
+        let due = match &self.pending_switch {
+            Some(switch) => end_of_table || switch.at == msg::SwitchAt::EndOfSlot,
+            None => false,
+        };
+
+        if due && let Some(switch) = self.pending_switch.take() {
+            if switch.one_shot {
+                // A one-shot table interrupting another one-shot returns to the original table
+                self.resume.get_or_insert(Resume {
+                    schedule_table_name: self.schedule_table_name.clone(),
+                    table_index: self.table_index,
+                });
+            } else {
+                self.resume = None;
+            }
+
+            self.schedule_table_name = switch.schedule_table_name;
+            self.table_index = 0;
+        }
+    }
+}
+
+/// Run reader in virtual time for duration, reading once per step.
+/// Returns the frames read together with the virtual time they were read at.
+pub fn run_virtual(
+    reader: &mut impl FrameReader,
+    clock: &VirtualClock,
+    duration: Duration,
+    step: Duration,
+) -> Vec<(Duration, frame::Frame)> {
+    let end = clock.now() + duration;
+    let mut frames = Vec::new();
+
+    while clock.now() < end {
+        if let Some(frame) = reader.try_read() {
+            frames.push((clock.now(), frame));
+        }
+
+        clock.advance(step);
+    }
+
+    frames
 }
 
 pub struct SlaveSimulator {
     name: String,
+    ldf: ldf::LDF,
+    faults: FaultInjector,
+    e2e: E2eProtector,
+    script: Option<Script>,
+    /// Whether the slaves answer headers at all
+    respond: bool,
+    // Responses and the time their transmission ends
+    responses: VecDeque<(Duration, frame::Frame)>,
+    timing: FrameTiming,
+    clock: Arc<dyn Clock>,
+    busy_until: Duration,
+    bus_state: BusState,
+    diag: DiagResponder,
+    diag_response: Option<Vec<u8>>,
 }
 
 impl SlaveSimulator {
-    pub fn new(name: &str) -> Result<impl Master> {
+    /// Slaves answer headers only if respond is set, with the init values of the LDF
+    pub fn new(name: &str, ldf: ldf::LDF, respond: bool, options: Options) -> Result<impl Master> {
         log::info!("SlaveSimulator::new {name}");
 
-        Ok(SlaveSimulator { name: name.into() })
+        let Options {
+            faults,
+            e2e,
+            script,
+            timing,
+            clock,
+        } = options;
+
+        Ok(SlaveSimulator {
+            name: name.into(),
+            diag: DiagResponder::new(&ldf),
+            diag_response: None,
+            ldf,
+            faults,
+            e2e,
+            script,
+            respond,
+            responses: VecDeque::new(),
+            timing,
+            busy_until: clock.now(),
+            clock,
+            bus_state: BusState::Awake,
+        })
     }
 
     fn try_read(&mut self) -> Option<frame::Frame> {
-        None
+        let (end, _) = self.responses.front()?;
+
+        if self.clock.now() < *end {
+            return None;
+        }
+
+        self.responses.pop_front().map(|(_, frame)| frame)
+    }
+
+    // Headers are sent one after another, a frame ends once its response has been transmitted
+    fn respond(&mut self, frame: frame::Frame) {
+        let start = self.clock.now().max(self.busy_until);
+        let end = start + self.timing.frame(frame.msg.len());
+
+        self.busy_until = end;
+        self.responses.push_back((end, frame));
+    }
+
+    fn write(&mut self, frame: &frame::Frame) -> Result<()> {
+        if frame.is_go_to_sleep() {
+            log::info!("{} slaves going to sleep", self.name);
+
+            self.bus_state = BusState::Sleeping;
+        } else if frame.is_wakeup() {
+            log::info!("{} slaves woken up", self.name);
+
+            self.bus_state = BusState::Awake;
+        } else if frame.id == frame::MASTER_REQ_ID {
+            // A new request discards any response not yet fetched
+            self.diag_response = self.diag.handle_request(&frame.msg);
+        } else if let Some(script) = self.script.as_mut()
+            && let Some(ldf_frame) = self.ldf.frames.values().find(|f| f.id == frame.id)
+        {
+            script.on_frame_received(&self.ldf, ldf_frame, &frame.msg);
+        }
+
+        Ok(())
+    }
+
+    fn request_update(&mut self, id: u32) -> Result<()> {
+        // A break wakes sleeping slaves, but they are not ready to respond to this header
+        if self.bus_state == BusState::Sleeping {
+            log::info!("{} slaves woken up by header {id:#x}", self.name);
+
+            self.bus_state = BusState::Awake;
+            if self.respond {
+                self.respond(frame::Frame::new(id, vec![]));
+            }
+            return Ok(());
+        }
+
+        // Silent slaves leave every header unanswered, diagnostic ones included
+        if !self.respond {
+            return Ok(());
+        }
+
+        if id == frame::SLAVE_RESP_ID {
+            let msg = self.diag_response.take().unwrap_or_default();
+            self.respond(frame::Frame::new(id, msg));
+            return Ok(());
+        }
+
+        let publisher = self
+            .ldf
+            .frames
+            .values()
+            .find(|f| self.diag.frame_id(f) == Some(id) && f.owner != self.ldf.nodes.master);
+
+        // A header nobody responds to is read back without payload
+        let msg = match publisher {
+            Some(frame) => {
+                let mut msg = self.ldf.init_payload(frame);
+
+                if let Some(script) = self.script.as_mut() {
+                    script.on_slot(&self.ldf, frame, &mut msg);
+                }
+
+                self.e2e.protect(frame.id, &mut msg);
+                msg
+            }
+            None => vec![],
+        };
+
+        if let Some(frame) =
+            self.faults
+                .inject(&self.ldf, publisher, None, frame::Frame::new(id, msg))
+        {
+            self.respond(frame);
+        }
+
+        Ok(())
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        if self.bus_state == BusState::Awake {
+            return Err(anyhow::anyhow!("{} bus is not sleeping", self.name));
+        }
+
+        log::info!("{} slave sending wake-up", self.name);
+
+        self.bus_state = BusState::Awake;
+        self.responses
+            .push_back((self.clock.now(), frame::Frame::wakeup()));
+        Ok(())
     }
 }
 
@@ -81,6 +469,18 @@ impl FrameReader for MasterSimulator {
     fn try_read(&mut self) -> Option<frame::Frame> {
         MasterSimulator::try_read(self)
     }
+
+    fn switch_schedule(&mut self, switch: &msg::SwitchSchedule) -> Result<()> {
+        MasterSimulator::switch_schedule(self, switch)
+    }
+
+    fn sleep(&mut self) -> Result<()> {
+        MasterSimulator::sleep(self)
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        MasterSimulator::wakeup(self)
+    }
 }
 
 impl FrameReader for SlaveSimulator {
@@ -91,21 +491,47 @@ impl FrameReader for SlaveSimulator {
     fn try_read(&mut self) -> Option<frame::Frame> {
         SlaveSimulator::try_read(self)
     }
+
+    fn sleep(&mut self) -> Result<()> {
+        Err(anyhow::anyhow!(
+            "{} simulates slaves, only the master can put the bus to sleep",
+            self.name
+        ))
+    }
+
+    fn wakeup(&mut self) -> Result<()> {
+        SlaveSimulator::wakeup(self)
+    }
 }
 
 impl Slave for MasterSimulator {
-    fn update(&mut self, _f: &frame::Frame) -> Result<()> {
+    fn update(&mut self, f: &frame::Frame) -> Result<()> {
+        // A slave may wake up the sleeping bus
+        if f.is_wakeup() {
+            if self.bus_state == BusState::Sleeping {
+                self.wake();
+            }
+        } else if let Some(script) = self.script.as_mut()
+            && let Some(ldf_frame) = self
+                .ldf
+                .frames
+                .values()
+                .find(|ldf_frame| ldf_frame.id == f.id)
+        {
+            script.on_frame_received(&self.ldf, ldf_frame, &f.msg);
+        }
+
         Ok(())
     }
 }
 
 impl Master for SlaveSimulator {
-    fn write(&mut self, _frame: &frame::Frame) -> Result<()> {
-        Ok(())
+    fn write(&mut self, frame: &frame::Frame) -> Result<()> {
+        SlaveSimulator::write(self, frame)
     }
 
-    fn request_update(&mut self, _id: u32) -> Result<()> {
-        Ok(())
+    fn request_update(&mut self, id: u32) -> Result<()> {
+        SlaveSimulator::request_update(self, id)
     }
 }
 
@@ -120,3 +546,432 @@ impl Drop for MasterSimulator {
         log::info!("MasterSimulator::drop {}", self.name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    const BASE_TICK: Duration = Duration::from_millis(5);
+
+    fn timing() -> FrameTiming {
+        FrameTiming::new(19_200, &msg::Timing::default())
+    }
+
+    fn new_master_simulator(schedule_table_name: &'static str) -> (impl Slave, Arc<VirtualClock>) {
+        let ldf = ldf::parse_file("src/testdata/schedules.ldf").unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        let e2e = E2eProtector::new(&[], &ldf).unwrap();
+        let sim = MasterSimulator::new(
+            "test",
+            ldf,
+            schedule_table_name,
+            Options {
+                faults: FaultInjector::new(&[], 0),
+                e2e,
+                script: None,
+                timing: timing(),
+                clock: clock.clone(),
+            },
+        )
+        .unwrap();
+
+        (sim, clock)
+    }
+
+    fn new_slave_simulator(
+        respond: bool,
+        faults: &[msg::FaultRule],
+    ) -> (impl Master + use<>, Arc<VirtualClock>) {
+        let ldf = ldf::parse_file("src/testdata/schedules.ldf").unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        let e2e = E2eProtector::new(&[], &ldf).unwrap();
+        let sim = SlaveSimulator::new(
+            "test",
+            ldf,
+            respond,
+            Options {
+                faults: FaultInjector::new(faults, 0),
+                e2e,
+                script: None,
+                timing: timing(),
+                clock: clock.clone(),
+            },
+        )
+        .unwrap();
+
+        (sim, clock)
+    }
+
+    fn read_ids(reader: &mut impl FrameReader, clock: &VirtualClock, ticks: u32) -> Vec<u32> {
+        run_virtual(reader, clock, BASE_TICK * ticks, BASE_TICK)
+            .into_iter()
+            .map(|(_, f)| f.id)
+            .collect()
+    }
+
+    fn switch(schedule_table_name: &str, at: msg::SwitchAt, one_shot: bool) -> msg::SwitchSchedule {
+        msg::SwitchSchedule {
+            host_device: "lin0".to_string(),
+            schedule_table_name: schedule_table_name.to_string(),
+            at,
+            one_shot,
+        }
+    }
+
+    #[test]
+    fn test_run_schedule_table() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+
+        assert_eq!(read_ids(&mut sim, &clock, 8), vec![0x10, 0x11, 0x12, 0x10]);
+    }
+
+    #[test]
+    fn test_switch_schedule_at_end_of_slot() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+        assert_eq!(read_ids(&mut sim, &clock, 2), vec![0x10]);
+
+        sim.switch_schedule(&switch("OtherTable", msg::SwitchAt::EndOfSlot, false))
+            .unwrap();
+
+        assert_eq!(read_ids(&mut sim, &clock, 4), vec![0x12, 0x12, 0x12]);
+    }
+
+    #[test]
+    fn test_switch_schedule_at_end_of_table() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+        assert_eq!(read_ids(&mut sim, &clock, 2), vec![0x10]);
+
+        sim.switch_schedule(&switch("OtherTable", msg::SwitchAt::EndOfTable, false))
+            .unwrap();
+
+        assert_eq!(read_ids(&mut sim, &clock, 7), vec![0x11, 0x12, 0x12, 0x12]);
+    }
+
+    #[test]
+    fn test_one_shot_schedule_returns_to_interrupted_table() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+        assert_eq!(read_ids(&mut sim, &clock, 2), vec![0x10]);
+
+        sim.switch_schedule(&switch("DiagTable", msg::SwitchAt::EndOfSlot, true))
+            .unwrap();
+
+        assert_eq!(
+            read_ids(&mut sim, &clock, 9),
+            vec![0x11, 0x11, 0x11, 0x12, 0x10]
+        );
+    }
+
+    #[test]
+    fn test_switch_to_unknown_schedule_fails() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+
+        assert!(
+            sim.switch_schedule(&switch("NoSuchTable", msg::SwitchAt::EndOfSlot, false))
+                .is_err()
+        );
+        assert_eq!(read_ids(&mut sim, &clock, 2), vec![0x10]);
+    }
+
+    #[test]
+    fn test_slave_simulator_is_silent_without_rules() {
+        let (mut sim, clock) = new_slave_simulator(false, &[]);
+
+        sim.request_update(0x11).unwrap();
+        sim.request_update(0x3f).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(sim.try_read(), None);
+    }
+
+    #[test]
+    fn test_slave_simulator_responds_to_headers() {
+        let (mut sim, clock) = new_slave_simulator(true, &[]);
+
+        sim.request_update(0x11).unwrap();
+        sim.request_update(0x3f).unwrap();
+
+        // Nothing is visible until the response has been transmitted
+        assert_eq!(sim.try_read(), None);
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(
+            sim.try_read(),
+            Some(frame::Frame::new(0x11, vec![0xff, 0xff]))
+        );
+        assert_eq!(sim.try_read(), Some(frame::Frame::new(0x3f, vec![])));
+        assert_eq!(sim.try_read(), None);
+    }
+
+    #[test]
+    fn test_slave_simulator_injects_faults() {
+        let (mut sim, clock) = new_slave_simulator(
+            true,
+            &[msg::FaultRule {
+                frame_id: Some(0x11),
+                slot: None,
+                fault: msg::Fault::DropResponse,
+                probability: None,
+                sequence: Some(vec![true, false]),
+            }],
+        );
+
+        sim.request_update(0x11).unwrap();
+        sim.request_update(0x11).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(sim.try_read().map(|f| f.id), Some(0x11));
+        assert_eq!(sim.try_read(), None);
+    }
+
+    #[test]
+    fn test_slave_simulator_answers_diagnostic_requests() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        let e2e = E2eProtector::new(&[], &ldf).unwrap();
+        let mut sim = SlaveSimulator::new(
+            "test",
+            ldf,
+            true,
+            Options {
+                faults: FaultInjector::new(&[], 0),
+                e2e,
+                script: None,
+                timing: timing(),
+                clock: clock.clone(),
+            },
+        )
+        .unwrap();
+
+        // ReadByIdentifier product id of DEVS1, using wildcards
+        sim.write(&frame::Frame::new(
+            frame::MASTER_REQ_ID,
+            vec![0x86, 0x06, 0xb2, 0x00, 0xff, 0x7f, 0xff, 0xff],
+        ))
+        .unwrap();
+        sim.request_update(frame::SLAVE_RESP_ID).unwrap();
+        sim.request_update(frame::SLAVE_RESP_ID).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(
+            sim.try_read(),
+            Some(frame::Frame::new(
+                frame::SLAVE_RESP_ID,
+                vec![0x86, 0x06, 0xf2, 0x7f, 0x00, 0x04, 0x02, 0x00]
+            ))
+        );
+        assert_eq!(sim.try_read().map(|f| f.msg), Some(vec![]));
+
+        // AssignFrameIdRange moves DEVS2LIN01Fr04 from 0x10 to 0x0b
+        sim.write(&frame::Frame::new(
+            frame::MASTER_REQ_ID,
+            vec![0x81, 0x06, 0xb7, 0x01, 0x8b, 0xff, 0xff, 0xff],
+        ))
+        .unwrap();
+        sim.request_update(0x10).unwrap();
+        sim.request_update(0x0b).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(sim.try_read().map(|f| f.msg.len()), Some(0));
+        assert_eq!(sim.try_read().map(|f| f.msg.len()), Some(7));
+    }
+
+    #[test]
+    fn test_slave_simulator_runs_script() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let e2e = E2eProtector::new(&[], &ldf).unwrap();
+        let script = Script::load("src/testdata/respond.rhai").unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        let mut sim = SlaveSimulator::new(
+            "test",
+            ldf,
+            true,
+            Options {
+                faults: FaultInjector::new(&[], 0),
+                e2e,
+                script: Some(script),
+                timing: timing(),
+                clock: clock.clone(),
+            },
+        )
+        .unwrap();
+
+        sim.request_update(5).unwrap();
+        sim.write(&frame::Frame::new(3, vec![0b1000_0000, 0]))
+            .unwrap();
+        sim.request_update(5).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        // SomeQf starts at its init value 3 and the script sets it to 1
+        assert_eq!(sim.try_read().map(|f| f.msg), Some(vec![0, 192, 251]));
+        assert_eq!(sim.try_read().map(|f| f.msg), Some(vec![0, 192, 249]));
+    }
+
+    #[test]
+    fn test_master_simulator_injects_faults_per_slot() {
+        let ldf = ldf::parse_file("src/testdata/schedules.ldf").unwrap();
+        let faults = FaultInjector::new(
+            &[msg::FaultRule {
+                frame_id: None,
+                slot: Some(0),
+                fault: msg::Fault::Length(1),
+                probability: None,
+                sequence: None,
+            }],
+            0,
+        );
+        let clock = Arc::new(VirtualClock::new());
+        let e2e = E2eProtector::new(&[], &ldf).unwrap();
+        let mut sim = MasterSimulator::new(
+            "test",
+            ldf,
+            "NormalTable",
+            Options {
+                faults,
+                e2e,
+                script: None,
+                timing: timing(),
+                clock: clock.clone(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            run_virtual(&mut sim, &clock, BASE_TICK, BASE_TICK / 5)[0].1,
+            frame::Frame::new(0x10, vec![0])
+        );
+    }
+
+    #[test]
+    fn test_master_simulator_protects_frames() {
+        let ldf = ldf::parse_file("simulator/simulator.ldf").unwrap();
+        let e2e = E2eProtector::new(
+            &[msg::E2eRule {
+                frame_id: 3,
+                counter: Some(msg::CounterRule {
+                    signal: "SomeCntr".to_string(),
+                    wrap: None,
+                }),
+                checksum: None,
+            }],
+            &ldf,
+        )
+        .unwrap();
+        let clock = Arc::new(VirtualClock::new());
+        let mut sim = MasterSimulator::new(
+            "test",
+            ldf,
+            "DEVMLIN01Schedule01",
+            Options {
+                faults: FaultInjector::new(&[], 0),
+                e2e,
+                script: None,
+                timing: timing(),
+                clock: clock.clone(),
+            },
+        )
+        .unwrap();
+
+        // SomeCntr is bits 2-3 of the first byte
+        let counters: Vec<u8> =
+            run_virtual(&mut sim, &clock, Duration::from_millis(100), BASE_TICK)
+                .into_iter()
+                .filter(|(_, f)| f.id == 3)
+                .map(|(_, f)| f.msg[0] >> 2 & 0b11)
+                .collect();
+
+        assert_eq!(counters[..5], [0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_master_simulator_sleep_and_wakeup() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+        assert_eq!(read_ids(&mut sim, &clock, 2), vec![0x10]);
+
+        sim.sleep().unwrap();
+        assert_eq!(read_ids(&mut sim, &clock, 3), vec![frame::MASTER_REQ_ID]);
+        assert!(read_ids(&mut sim, &clock, 1000).is_empty());
+        assert!(sim.sleep().is_err());
+
+        sim.wakeup().unwrap();
+        assert_eq!(sim.try_read(), Some(frame::Frame::wakeup()));
+
+        // Schedule restarts from the first slot after the wake-up delay
+        assert!(read_ids(&mut sim, &clock, 20).is_empty());
+        assert_eq!(read_ids(&mut sim, &clock, 4), vec![0x10, 0x11]);
+    }
+
+    #[test]
+    fn test_master_simulator_woken_by_slave() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+        sim.sleep().unwrap();
+        assert_eq!(
+            read_ids(&mut sim, &clock, 5),
+            vec![0x10, frame::MASTER_REQ_ID]
+        );
+
+        sim.update(&frame::Frame::wakeup()).unwrap();
+
+        assert_eq!(read_ids(&mut sim, &clock, 22), vec![0x10]);
+    }
+
+    #[test]
+    fn test_slave_simulator_sleeps_on_go_to_sleep() {
+        let (mut sim, clock) = new_slave_simulator(true, &[]);
+
+        sim.write(&frame::Frame::go_to_sleep()).unwrap();
+        sim.request_update(0x11).unwrap();
+        sim.request_update(0x11).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        // The first header only wakes the slaves up
+        assert_eq!(sim.try_read().map(|f| f.msg), Some(vec![]));
+        assert_eq!(sim.try_read().map(|f| f.msg), Some(vec![0xff, 0xff]));
+
+        assert!(sim.wakeup().is_err());
+        sim.write(&frame::Frame::go_to_sleep()).unwrap();
+        sim.wakeup().unwrap();
+        assert_eq!(sim.try_read(), Some(frame::Frame::wakeup()));
+    }
+
+    #[test]
+    fn test_frames_are_visible_after_transmission() {
+        let (mut sim, clock) = new_master_simulator("NormalTable");
+
+        let times: Vec<Duration> = run_virtual(
+            &mut sim,
+            &clock,
+            Duration::from_millis(30),
+            Duration::from_micros(100),
+        )
+        .into_iter()
+        .map(|(time, _)| time)
+        .collect();
+
+        // 64 bits for the master frame, 34 bits for the unanswered headers at 19200 bps
+        assert_eq!(
+            times,
+            vec![
+                Duration::from_micros(3400),
+                Duration::from_micros(11800),
+                Duration::from_micros(21800)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_virtual_time_runs_are_reproducible() {
+        let run = || {
+            let (mut sim, clock) = new_master_simulator("NormalTable");
+            run_virtual(&mut sim, &clock, Duration::from_secs(3600), BASE_TICK)
+        };
+
+        let frames = run();
+
+        // One frame per 10 ms slot for an hour
+        assert_eq!(frames.len(), 360_000);
+        assert_eq!(frames[3].0, Duration::from_millis(35));
+        assert_eq!(frames, run());
+    }
+}
@@ -0,0 +1,113 @@
+//! On-wire duration of LIN frames, used by software buses to emit frames at realistic times.
+use crate::msg;
+use std::time::Duration;
+
+/// Sync byte and protected identifier, 10 bits each including start and stop bit
+const SYNC_AND_PID_BITS: f64 = 20.0;
+const BYTE_BITS: f64 = 10.0;
+
+/// Baudrates used on LIN buses, a detected baudrate is locked onto one of them
+pub const COMMON_BAUDRATES: [u32; 5] = [2_400, 9_600, 10_417, 19_200, 20_000];
+
+/// Largest deviation in percent of a measured baudrate from the common baudrate it is locked onto,
+/// 19200 and 20000 bps are only 4 % apart
+const MAX_DEVIATION_PERCENT: f64 = 2.0;
+
+/// Common baudrate closest to the median of the baudrates measured on synch fields
+pub fn lock_baudrate(measured: &[u32]) -> Option<u32> {
+    let mut measured = measured.to_vec();
+    measured.sort_unstable();
+    let median = f64::from(*measured.get(measured.len() / 2)?);
+
+    let deviation = |baudrate: u32| (median - f64::from(baudrate)).abs() * 100.0 / median;
+
+    COMMON_BAUDRATES
+        .into_iter()
+        .min_by(|a, b| deviation(*a).total_cmp(&deviation(*b)))
+        .filter(|baudrate| deviation(*baudrate) <= MAX_DEVIATION_PERCENT)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameTiming {
+    baudrate: u32,
+    timing: msg::Timing,
+}
+
+impl FrameTiming {
+    pub fn new(baudrate: u32, timing: &msg::Timing) -> Self {
+        FrameTiming {
+            baudrate,
+            timing: *timing,
+        }
+    }
+
+    pub fn baudrate(&self) -> u32 {
+        self.baudrate
+    }
+
+    /// Break, break delimiter, sync and PID
+    pub fn header(&self) -> Duration {
+        self.bits(self.timing.break_bits + self.timing.break_delimiter_bits + SYNC_AND_PID_BITS)
+    }
+
+    /// Whole frame with data_len data bytes and checksum, a header only if data_len is 0
+    pub fn frame(&self, data_len: usize) -> Duration {
+        if data_len == 0 {
+            return self.header();
+        }
+
+        let response_bits = self.timing.response_space_bits
+            + (data_len + 1) as f64 * BYTE_BITS
+            + data_len as f64 * self.timing.inter_byte_space_bits;
+
+        self.header() + self.bits(response_bits)
+    }
+
+    fn bits(&self, bits: f64) -> Duration {
+        Duration::from_nanos((bits * 1e9 / f64::from(self.baudrate)).round() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_nominal_frame_duration() {
+        let timing = FrameTiming::new(20_000, &msg::Timing::default());
+
+        // 34 bit header and 90 bit response for 8 bytes
+        assert_eq!(timing.header(), Duration::from_micros(1700));
+        assert_eq!(timing.frame(8), Duration::from_micros(6200));
+    }
+
+    #[test]
+    fn test_lock_baudrate() {
+        assert_eq!(lock_baudrate(&[19_180, 19_230, 19_210]), Some(19_200));
+        assert_eq!(lock_baudrate(&[10_400]), Some(10_417));
+        assert_eq!(lock_baudrate(&[20_050, 19_990, 2_400]), Some(20_000));
+
+        // A rate between the common ones is not guessed
+        assert_eq!(lock_baudrate(&[15_000]), None);
+        assert_eq!(lock_baudrate(&[]), None);
+    }
+
+    #[test]
+    fn test_spaces_extend_frame() {
+        let timing = FrameTiming::new(
+            10_000,
+            &msg::Timing {
+                response_space_bits: 2.0,
+                inter_byte_space_bits: 1.0,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            timing.frame(2),
+            Duration::from_micros(3400 + 200 + 3000 + 200)
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! In-process LIN bus shared by every bus started with the same virtual bus name.
+//!
+//! Nodes behave like Kvaser LIN nodes: every node reads all traffic on the bus, a master writes
+//! frames and sends headers, and slaves keep the responses they publish until updated.
+//! Frames are read once they have been transmitted on the wire.
+use crate::clock::Clock;
+use crate::frame::{Frame, FrameFlags, FrameInfo};
+use crate::masterslave::{FrameReader, Master, Slave};
+use crate::msg::HostMode;
+use crate::noechoslave::NoEchoSlave;
+use crate::timing::FrameTiming;
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Frames a node has not read yet, the oldest are dropped beyond this
+const RX_QUEUE_LIMIT: usize = 1024;
+
+static VIRTUAL_BUSES: Lazy<Mutex<HashMap<String, Weak<Mutex<Bus>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Bus {
+    has_master: bool,
+    next_node_id: usize,
+    nodes: HashMap<usize, Node>,
+    timing: FrameTiming,
+    clock: Arc<dyn Clock>,
+    busy_until: Duration,
+}
+
+#[derive(Default)]
+struct Node {
+    // Frames and the time their transmission ends
+    rx: VecDeque<(Duration, Frame)>,
+    responses: HashMap<u32, Vec<u8>>,
+}
+
+impl Bus {
+    // Frames are transmitted one after another
+    fn transmit(&mut self, frame: &Frame) {
+        let start = self.clock.now().max(self.busy_until);
+        let end = start + self.timing.frame(frame.msg.len());
+
+        self.busy_until = end;
+        self.broadcast(end, frame);
+    }
+
+    fn broadcast(&mut self, end: Duration, frame: &Frame) {
+        for node in self.nodes.values_mut() {
+            if node.rx.len() >= RX_QUEUE_LIMIT {
+                log::warn!(
+                    "Virtual bus node queue full, dropping {:?}",
+                    node.rx.front()
+                );
+                node.rx.pop_front();
+            }
+
+            node.rx.push_back((end, frame.clone()));
+        }
+    }
+
+    // Slaves publishing a response for the header answer it. Differing responses collide and are
+    // lost, which receivers see as a bit error.
+    fn respond(&self, id: u32) -> Frame {
+        let mut responses = self.nodes.values().filter_map(|n| n.responses.get(&id));
+
+        let (msg, flags) = match responses.next() {
+            Some(first) if responses.all(|r| r == first) => (first.clone(), None),
+            Some(_) => {
+                log::warn!("Virtual bus response collision for frame id {id:#x}");
+                (vec![], Some(FrameFlags::BIT_ERROR))
+            }
+            None => (vec![], Some(FrameFlags::NODATA)),
+        };
+
+        Frame {
+            id,
+            msg,
+            info: flags.map(|flags| FrameInfo {
+                flags,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+pub struct VirtualLin {
+    name: String,
+    bus_name: String,
+    bus: Arc<Mutex<Bus>>,
+    node_id: usize,
+    host_mode: HostMode,
+}
+
+impl VirtualLin {
+    pub fn new_slave(
+        name: &str,
+        bus_name: &str,
+        timing: FrameTiming,
+        clock: Arc<dyn Clock>,
+    ) -> Result<impl Slave> {
+        Ok(NoEchoSlave::new(VirtualLin::new(
+            name,
+            bus_name,
+            HostMode::Slave,
+            timing,
+            clock,
+        )?))
+    }
+
+    pub fn new_master(
+        name: &str,
+        bus_name: &str,
+        timing: FrameTiming,
+        clock: Arc<dyn Clock>,
+    ) -> Result<impl Master> {
+        VirtualLin::new(name, bus_name, HostMode::Master, timing, clock)
+    }
+
+    // The first node on a bus decides its timing and clock
+    fn new(
+        name: &str,
+        bus_name: &str,
+        host_mode: HostMode,
+        timing: FrameTiming,
+        clock: Arc<dyn Clock>,
+    ) -> Result<VirtualLin> {
+        let mut buses = VIRTUAL_BUSES.lock().unwrap();
+        buses.retain(|_, bus| bus.strong_count() > 0);
+
+        let bus = match buses.get(bus_name).and_then(Weak::upgrade) {
+            Some(bus) => bus,
+            None => {
+                log::info!("Creating virtual bus {bus_name}");
+
+                let bus = Arc::new(Mutex::new(Bus {
+                    has_master: false,
+                    next_node_id: 0,
+                    nodes: HashMap::new(),
+                    timing,
+                    busy_until: clock.now(),
+                    clock,
+                }));
+                buses.insert(bus_name.to_string(), Arc::downgrade(&bus));
+                bus
+            }
+        };
+
+        let node_id = {
+            let mut state = bus.lock().unwrap();
+
+            if state.timing.baudrate() != timing.baudrate() {
+                return Err(anyhow::anyhow!(
+                    "Virtual bus {bus_name} runs at {} bps",
+                    state.timing.baudrate()
+                ));
+            }
+
+            if host_mode == HostMode::Master {
+                if state.has_master {
+                    return Err(anyhow::anyhow!(
+                        "Virtual bus {bus_name} already has a master"
+                    ));
+                }
+                state.has_master = true;
+            }
+
+            let node_id = state.next_node_id;
+            state.next_node_id += 1;
+            state.nodes.insert(node_id, Node::default());
+            node_id
+        };
+
+        log::info!("VirtualLin::new {name} as {host_mode:?} on {bus_name}");
+
+        Ok(VirtualLin {
+            name: name.to_string(),
+            bus_name: bus_name.to_string(),
+            bus,
+            node_id,
+            host_mode,
+        })
+    }
+}
+
+impl FrameReader for VirtualLin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn try_read(&mut self) -> Option<Frame> {
+        let mut bus = self.bus.lock().unwrap();
+        let now = bus.clock.now();
+
+        let rx = &mut bus.nodes.get_mut(&self.node_id)?.rx;
+        let (end, _) = rx.front()?;
+
+        if now < *end {
+            return None;
+        }
+
+        rx.pop_front().map(|(_, frame)| frame)
+    }
+}
+
+impl Slave for VirtualLin {
+    fn update(&mut self, f: &Frame) -> Result<()> {
+        let mut bus = self.bus.lock().unwrap();
+
+        // A slave may wake up the bus at any time
+        if f.is_wakeup() {
+            let now = bus.clock.now();
+            bus.broadcast(now, f);
+            return Ok(());
+        }
+
+        if let Some(node) = bus.nodes.get_mut(&self.node_id) {
+            node.responses.insert(f.id, f.msg.clone());
+        }
+
+        Ok(())
+    }
+}
+
+impl Master for VirtualLin {
+    fn write(&mut self, frame: &Frame) -> Result<()> {
+        self.bus.lock().unwrap().transmit(frame);
+
+        Ok(())
+    }
+
+    fn request_update(&mut self, id: u32) -> Result<()> {
+        let mut bus = self.bus.lock().unwrap();
+
+        let frame = bus.respond(id);
+        bus.transmit(&frame);
+
+        Ok(())
+    }
+}
+
+impl Drop for VirtualLin {
+    fn drop(&mut self) {
+        log::info!("VirtualLin::drop {} on {}", self.name, self.bus_name);
+
+        let mut bus = self.bus.lock().unwrap();
+        bus.nodes.remove(&self.node_id);
+
+        if self.host_mode == HostMode::Master {
+            bus.has_master = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::msg;
+
+    use pretty_assertions::assert_eq;
+
+    fn timing() -> FrameTiming {
+        FrameTiming::new(19_200, &msg::Timing::default())
+    }
+
+    fn new_master(bus_name: &'static str, clock: &Arc<VirtualClock>) -> Result<impl Master> {
+        VirtualLin::new_master("master", bus_name, timing(), clock.clone())
+    }
+
+    fn new_slave(bus_name: &'static str, clock: &Arc<VirtualClock>) -> impl Slave {
+        VirtualLin::new_slave("slave", bus_name, timing(), clock.clone()).unwrap()
+    }
+
+    fn frame(id: u32, msg: &[u8]) -> Frame {
+        Frame::new(id, msg.to_vec())
+    }
+
+    fn header(id: u32, flags: FrameFlags) -> Frame {
+        Frame {
+            id,
+            msg: vec![],
+            info: Some(FrameInfo {
+                flags,
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_master_and_slaves_exchange_frames() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut master = new_master("test-exchange", &clock).unwrap();
+        let mut slave1 = new_slave("test-exchange", &clock);
+        let mut slave2 = new_slave("test-exchange", &clock);
+
+        master.write(&frame(0x10, &[1, 2])).unwrap();
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(slave1.try_read(), Some(frame(0x10, &[1, 2])));
+        assert_eq!(slave2.try_read(), Some(frame(0x10, &[1, 2])));
+        assert_eq!(master.try_read(), Some(frame(0x10, &[1, 2])));
+
+        // Responses stay published until updated, the responding slave sees no echo
+        slave1.update(&frame(0x11, &[3])).unwrap();
+        master.request_update(0x11).unwrap();
+        master.request_update(0x11).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(master.try_read(), Some(frame(0x11, &[3])));
+        assert_eq!(master.try_read(), Some(frame(0x11, &[3])));
+        assert_eq!(slave2.try_read(), Some(frame(0x11, &[3])));
+        assert_eq!(slave1.try_read(), Some(frame(0x11, &[])));
+
+        // Nobody answers
+        master.request_update(0x12).unwrap();
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(slave2.try_read(), Some(frame(0x11, &[3])));
+        assert_eq!(slave2.try_read(), Some(header(0x12, FrameFlags::NODATA)));
+    }
+
+    #[test]
+    fn test_differing_responses_collide() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut master = new_master("test-collision", &clock).unwrap();
+        let mut slave1 = new_slave("test-collision", &clock);
+        let mut slave2 = new_slave("test-collision", &clock);
+
+        slave1.update(&frame(0x11, &[1])).unwrap();
+        slave2.update(&frame(0x11, &[2])).unwrap();
+        master.request_update(0x11).unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        assert_eq!(master.try_read(), Some(header(0x11, FrameFlags::BIT_ERROR)));
+    }
+
+    #[test]
+    fn test_single_master_per_bus() {
+        let clock = Arc::new(VirtualClock::new());
+        let master = new_master("test-single-master", &clock).unwrap();
+        assert!(new_master("test-single-master", &clock).is_err());
+
+        drop(master);
+        assert!(new_master("test-single-master", &clock).is_ok());
+    }
+
+    #[test]
+    fn test_buses_are_separated_by_name() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut master = new_master("test-bus-a", &clock).unwrap();
+        let mut slave = new_slave("test-bus-b", &clock);
+
+        master.write(&frame(0x10, &[1])).unwrap();
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(slave.try_read(), None);
+    }
+
+    #[test]
+    fn test_frames_are_transmitted_one_after_another() {
+        let clock = Arc::new(VirtualClock::new());
+        let mut master = new_master("test-timing", &clock).unwrap();
+
+        // 64 bits each at 19200 bps
+        master.write(&frame(0x10, &[1, 2])).unwrap();
+        master.write(&frame(0x11, &[3, 4])).unwrap();
+
+        clock.advance(Duration::from_millis(3));
+        assert_eq!(master.try_read(), None);
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(master.try_read(), Some(frame(0x10, &[1, 2])));
+        assert_eq!(master.try_read(), None);
+
+        clock.advance(Duration::from_millis(3));
+        assert_eq!(master.try_read(), Some(frame(0x11, &[3, 4])));
+    }
+
+    #[test]
+    fn test_baudrate_must_match_bus() {
+        let clock = Arc::new(VirtualClock::new());
+        let _master = new_master("test-baudrate", &clock).unwrap();
+
+        let timing = FrameTiming::new(9_600, &msg::Timing::default());
+        assert!(VirtualLin::new_slave("slave", "test-baudrate", timing, clock.clone()).is_err());
+    }
+}
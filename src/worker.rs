@@ -1,22 +1,56 @@
+use crate::clock::{Clock, Ticker, VirtualClock, WallClock};
+use crate::e2e::E2eProtector;
+use crate::fault::FaultInjector;
 use crate::frame;
-use crate::kvaser::KvaserLinux;
+use crate::health::{Health, Supervised};
+use crate::kvaser;
+use crate::kvaser_channel::KvaserChannel;
 use crate::ldf;
-use crate::masterslave::{FrameReader, Master, Slave};
+use crate::learn::Learning;
+use crate::masterslave::{BusEvent, FrameReader, Master, Slave};
 use crate::msg;
 use crate::msg::HostMode;
-use crate::simulator::{MasterSimulator, SlaveSimulator};
+use crate::replay::Replayer;
+use crate::script::Script;
+use crate::simulator::{self, MasterSimulator, SlaveSimulator};
+use crate::timing::FrameTiming;
+use crate::virtual_lin::VirtualLin;
 use anyhow::Result;
 use socketcan::frame::AsPtr;
 use socketcan::{self, Frame};
+use std::sync::Arc;
 use tokio::select;
-use tokio::sync::oneshot;
-use tokio::time::{Duration, interval};
+use tokio::sync::{Notify, mpsc, oneshot, watch};
+use tokio::task;
+use tokio::time::Duration;
+
+/// Software buses and replayed frames are due at arbitrary times, so poll more often than a LIN base tick
+const POLL_INTERVAL_MS: u64 = 1;
+
+/// Longest a hardware bus goes without a tick, so its health is checked without traffic
+const SUPERVISION_INTERVAL_MS: u64 = 100;
+
+/// Runtime command for a running bus
+#[derive(Debug)]
+pub enum Command {
+    SwitchSchedule(msg::SwitchSchedule),
+    Sleep,
+    Wakeup,
+}
+
+/// Command sent to a running worker together with the channel for its reply
+pub struct Request {
+    pub command: Command,
+    pub reply_tx: oneshot::Sender<Result<()>>,
+}
 
 pub async fn run(
     vbus_id: &str,
     config: msg::Config,
     cancel_rx: oneshot::Receiver<()>,
-    ready_tx: oneshot::Sender<Result<()>>,
+    command_rx: mpsc::Receiver<Request>,
+    ready_tx: oneshot::Sender<Result<u32>>,
+    health_tx: watch::Sender<Option<Health>>,
 ) -> Result<()> {
     let ready_tx = std::sync::Mutex::new(Some(ready_tx));
     let fail = |e: anyhow::Error| -> anyhow::Error {
@@ -25,11 +59,6 @@ pub async fn run(
         }
         e
     };
-    let succeed = || {
-        if let Some(tx) = ready_tx.lock().unwrap().take() {
-            let _ = tx.send(Ok(()));
-        }
-    };
 
     log::info!("Opening vbus {vbus_id}");
 
@@ -37,10 +66,50 @@ pub async fn run(
 
     log::info!("Opened vbus {vbus_id}");
 
+    let baudrate = resolve_baudrate(&config).await.map_err(&fail)?;
+
+    // Ready with the baudrate of the bus, reported to the caller
+    let succeed = || {
+        if let Some(tx) = ready_tx.lock().unwrap().take() {
+            let _ = tx.send(Ok(baudrate));
+        }
+    };
+
     match config.plugin {
         msg::Plugin::Simulator(sim_config) => {
             let ldf = ldf::parse_file(&sim_config.database).map_err(&fail)?;
-            let base_tick_ms = Duration::from_millis(ldf.nodes.base_tick_ms as u64);
+            let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+            let timing = FrameTiming::new(baudrate, &sim_config.timing);
+            let faults = FaultInjector::new(&sim_config.faults, sim_config.seed);
+            let e2e = E2eProtector::new(&sim_config.e2e, &ldf).map_err(&fail)?;
+            let script = match &sim_config.script {
+                Some(path) => Some(Script::load(path).map_err(&fail)?),
+                None => None,
+            };
+
+            let (clock, ticker): (Arc<dyn Clock>, Ticker) = match sim_config.clock {
+                msg::ClockMode::Wall => (Arc::new(WallClock::new()), Ticker::wall(poll_interval)),
+                msg::ClockMode::Virtual => {
+                    log::info!(
+                        "{} running in virtual time at speed {}",
+                        sim_config.name,
+                        sim_config.speed
+                    );
+
+                    // Virtual time steps a base tick, schedule slots are whole base ticks
+                    let step = Duration::from_millis(u64::from(ldf.nodes.base_tick_ms.max(1)));
+                    let clock = Arc::new(VirtualClock::new());
+                    (clock.clone(), Ticker::paced(clock, step, sim_config.speed))
+                }
+            };
+
+            let options = simulator::Options {
+                faults,
+                e2e,
+                script,
+                timing,
+                clock,
+            };
 
             match sim_config.host_mode {
                 HostMode::Slave => {
@@ -48,58 +117,245 @@ pub async fn run(
                         &sim_config.name,
                         ldf,
                         &sim_config.schedule_table_name,
+                        options,
                     )
                     .map_err(&fail)?;
                     succeed();
-                    run_slave_role(&mut slave, &vbus, cancel_rx, base_tick_ms).await
+                    run_slave_role(&mut slave, &vbus, cancel_rx, command_rx, ticker).await
                 }
 
                 HostMode::Master => {
-                    let mut master = SlaveSimulator::new(&sim_config.name).map_err(&fail)?;
+                    let mut master =
+                        SlaveSimulator::new(&sim_config.name, ldf, sim_config.respond, options)
+                            .map_err(&fail)?;
                     succeed();
-                    run_master_role(&mut master, &vbus, cancel_rx, base_tick_ms).await
+                    run_master_role(&mut master, &vbus, cancel_rx, command_rx, ticker).await
                 }
             }
         }
 
-        msg::Plugin::Lin(lin_config) => {
-            let base_tick_ms = Duration::from_millis(u64::from(lin_config.base_tick_ms));
+        msg::Plugin::Replay(replay_config) => {
+            let ticker = Ticker::wall(Duration::from_millis(POLL_INTERVAL_MS));
 
-            match lin_config.host_mode {
+            match &replay_config.device_id {
+                None => {
+                    let mut slave = Replayer::new_slave(&replay_config).map_err(&fail)?;
+                    succeed();
+                    run_slave_role(&mut slave, &vbus, cancel_rx, command_rx, ticker).await
+                }
+
+                Some(device_id) => {
+                    let mut replayer = Replayer::new(&replay_config).map_err(&fail)?;
+                    // Replayed frames are due on the ticker, responses are read on it too
+                    let mut master = KvaserChannel::new_master(
+                        &replay_config.name,
+                        device_id,
+                        baudrate,
+                        msg::FrameModel::default(),
+                        Arc::new(Notify::new()),
+                    )
+                    .await
+                    .map_err(&fail)?;
+                    succeed();
+                    let res = run_replay_to_master(
+                        &mut replayer,
+                        &mut master,
+                        &vbus,
+                        cancel_rx,
+                        command_rx,
+                        ticker,
+                    )
+                    .await;
+                    close(&mut master).await;
+                    res
+                }
+            }
+        }
+
+        msg::Plugin::Virtual(virtual_config) => {
+            let ticker = Ticker::wall(Duration::from_millis(POLL_INTERVAL_MS));
+            let name = virtual_config.name.as_ref().unwrap_or(&config.host_device);
+            let timing = FrameTiming::new(baudrate, &virtual_config.timing);
+            let clock = Arc::new(WallClock::new());
+
+            match virtual_config.host_mode {
                 HostMode::Slave => {
-                    let mut slave = KvaserLinux::new_slave(
+                    let mut slave =
+                        VirtualLin::new_slave(name, &virtual_config.virtual_bus, timing, clock)
+                            .map_err(&fail)?;
+                    succeed();
+                    run_slave_role(&mut slave, &vbus, cancel_rx, command_rx, ticker).await
+                }
+
+                HostMode::Master => {
+                    let mut master =
+                        VirtualLin::new_master(name, &virtual_config.virtual_bus, timing, clock)
+                            .map_err(&fail)?;
+                    succeed();
+                    run_master_role(&mut master, &vbus, cancel_rx, command_rx, ticker).await
+                }
+            }
+        }
+
+        msg::Plugin::Lin(lin_config) => {
+            // The channel thread signals as soon as frames arrive
+            let notify = Arc::new(Notify::new());
+            let ticker = Ticker::notified(
+                notify.clone(),
+                Duration::from_millis(SUPERVISION_INTERVAL_MS),
+            );
+
+            // A master drives the traffic itself, only a slave can find the bus idle
+            let idle_timeout = match (lin_config.host_mode, lin_config.idle_timeout_ms) {
+                (HostMode::Master, _) | (_, 0) => None,
+                (HostMode::Slave, ms) => Some(Duration::from_millis(ms)),
+            };
+
+            let preload = match (lin_config.host_mode, &lin_config.database) {
+                (_, None) => vec![],
+                (HostMode::Slave, Some(database)) => {
+                    preload_frames(database, lin_config.node.as_deref()).map_err(&fail)?
+                }
+                (HostMode::Master, Some(_)) => {
+                    return Err(fail(anyhow::anyhow!(
+                        "Answering frames from a database requires host_mode slave"
+                    )));
+                }
+            };
+
+            match (lin_config.host_mode, &lin_config.learn) {
+                (HostMode::Slave, None) => {
+                    let slave = KvaserChannel::new_slave(
                         lin_config.name.as_ref().unwrap_or(&config.host_device),
                         &lin_config.device_id,
-                        u32::from(config.baudrate),
+                        baudrate,
+                        lin_config.model.clone(),
+                        notify,
                     )
+                    .await
                     .map_err(&fail)?;
+                    let mut slave = Supervised::new(slave, idle_timeout, health_tx);
+                    publish(&mut slave, &preload).map_err(&fail)?;
                     succeed();
-                    run_slave_role(&mut slave, &vbus, cancel_rx, base_tick_ms).await
+                    let res =
+                        run_slave_role(&mut slave, &vbus, cancel_rx, command_rx, ticker).await;
+                    close(&mut slave).await;
+                    res
                 }
 
-                HostMode::Master => {
-                    let mut master = KvaserLinux::new_master(
+                (HostMode::Slave, Some(ldf_path)) => {
+                    let slave = KvaserChannel::new_slave(
                         lin_config.name.as_ref().unwrap_or(&config.host_device),
                         &lin_config.device_id,
-                        u32::from(config.baudrate),
+                        baudrate,
+                        lin_config.model.clone(),
+                        notify,
                     )
+                    .await
                     .map_err(&fail)?;
+                    let mut slave = Learning::new(
+                        Supervised::new(slave, idle_timeout, health_tx),
+                        baudrate,
+                        Arc::new(WallClock::new()),
+                    );
+                    publish(&mut slave, &preload).map_err(&fail)?;
                     succeed();
-                    run_master_role(&mut master, &vbus, cancel_rx, base_tick_ms).await
+
+                    // Keep what was learned even if the bus failed
+                    let res =
+                        run_slave_role(&mut slave, &vbus, cancel_rx, command_rx, ticker).await;
+                    let written = slave.write_ldf(ldf_path, u32::from(lin_config.base_tick_ms));
+                    close(&mut slave).await;
+                    res.and(written)
+                }
+
+                (HostMode::Master, Some(_)) => Err(fail(anyhow::anyhow!(
+                    "Learning an LDF is passive and requires host_mode slave"
+                ))),
+
+                (HostMode::Master, None) => {
+                    let master = KvaserChannel::new_master(
+                        lin_config.name.as_ref().unwrap_or(&config.host_device),
+                        &lin_config.device_id,
+                        baudrate,
+                        lin_config.model.clone(),
+                        notify,
+                    )
+                    .await
+                    .map_err(&fail)?;
+                    let mut master = Supervised::new(master, idle_timeout, health_tx);
+                    succeed();
+                    let res =
+                        run_master_role(&mut master, &vbus, cancel_rx, command_rx, ticker).await;
+                    close(&mut master).await;
+                    res
                 }
             }
         }
     }
 }
 
+/// Release the interface of the bus, so it is free again once the worker is stopped
+async fn close(reader: &mut impl FrameReader) {
+    if let Some(closing) = reader.close() {
+        let _ = closing.await;
+    }
+}
+
+/// Baudrate of the bus, detected on the Kvaser channel of a slave if "auto"
+async fn resolve_baudrate(config: &msg::Config) -> Result<u32> {
+    match (config.baudrate, &config.plugin) {
+        (msg::BusBaudrate::Fixed(baudrate), _) => Ok(u32::from(baudrate)),
+
+        (msg::BusBaudrate::Auto(_), msg::Plugin::Lin(lin_config))
+            if lin_config.host_mode == HostMode::Slave =>
+        {
+            let name = lin_config
+                .name
+                .clone()
+                .unwrap_or_else(|| config.host_device.clone());
+            let device_id = lin_config.device_id.clone();
+            let model = lin_config.model.clone();
+
+            // The channel is opened on a blocking thread and closed again before the bus opens it
+            task::spawn_blocking(move || kvaser::detect_baudrate(&name, &device_id, &model)).await?
+        }
+
+        (msg::BusBaudrate::Auto(_), _) => Err(anyhow::anyhow!(
+            "Detecting the baudrate requires a lin bus with host_mode slave"
+        )),
+    }
+}
+
+/// Responses with their init values of the frames node publishes in the LDF
+fn preload_frames(database: &str, node: Option<&str>) -> Result<Vec<frame::Frame>> {
+    let ldf = ldf::parse_file(database)?;
+
+    Ok(ldf
+        .published_frames(node)?
+        .into_iter()
+        .map(|ldf_frame| frame::Frame::new(ldf_frame.id, ldf.init_payload(ldf_frame)))
+        .collect())
+}
+
+/// Answer the frames from the first header, before any update arrives from the vbus
+fn publish(slave: &mut impl Slave, frames: &[frame::Frame]) -> Result<()> {
+    for frame in frames {
+        log::debug!("{} preloading frame {frame:?}", slave.name());
+
+        slave.update(frame)?;
+    }
+
+    Ok(())
+}
+
 async fn run_slave_role(
     slave: &mut impl Slave,
     vbus: &socketcan::tokio::CanSocket,
     mut cancel_rx: oneshot::Receiver<()>,
-    poll_interval: Duration,
+    mut command_rx: mpsc::Receiver<Request>,
+    mut ticker: Ticker,
 ) -> Result<()> {
-    let mut ticker = interval(poll_interval);
-
     loop {
         select! {
             _ = ticker.tick() => {
@@ -111,6 +367,10 @@ async fn run_slave_role(
         return Ok(());
             }
 
+            Some(request) = command_rx.recv() => {
+        handle_command(slave, request)
+            }
+
             result = vbus.read_frame() => {
         match result {
             Ok(frame) => read_and_forward_network_slave_frame(slave, frame).await,
@@ -127,10 +387,9 @@ async fn run_master_role(
     master: &mut impl Master,
     vbus: &socketcan::tokio::CanSocket,
     mut cancel_rx: oneshot::Receiver<()>,
-    poll_interval: Duration,
+    mut command_rx: mpsc::Receiver<Request>,
+    mut ticker: Ticker,
 ) -> Result<()> {
-    let mut ticker = interval(poll_interval);
-
     loop {
         select! {
             _ = ticker.tick() => {
@@ -142,6 +401,10 @@ async fn run_master_role(
         return Ok(());
             }
 
+            Some(request) = command_rx.recv() => {
+        handle_command(master, request)
+            }
+
             result = vbus.read_frame() => {
         match result {
             Ok(frame) =>  read_and_forward_network_master_frame(master, frame).await?,
@@ -154,15 +417,79 @@ async fn run_master_role(
     }
 }
 
+async fn run_replay_to_master(
+    replayer: &mut impl FrameReader,
+    master: &mut impl Master,
+    vbus: &socketcan::tokio::CanSocket,
+    mut cancel_rx: oneshot::Receiver<()>,
+    mut command_rx: mpsc::Receiver<Request>,
+    mut ticker: Ticker,
+) -> Result<()> {
+    loop {
+        select! {
+            _ = ticker.tick() => {
+        if let Some(frame) = replayer.try_read() {
+            if frame.msg.is_empty() {
+            master.request_update(frame.id)?
+            } else {
+            master.write(&frame)?
+            }
+        }
+
+        read_and_forward_lin_frame(master, vbus).await?
+            }
+
+            _ = &mut cancel_rx => {
+        log::info!("Requested to stop");
+        return Ok(());
+            }
+
+            Some(request) = command_rx.recv() => {
+        handle_command(replayer, request)
+            }
+        }
+    }
+}
+
+fn handle_command(reader: &mut impl FrameReader, request: Request) {
+    log::debug!("{} handling command {:?}", reader.name(), request.command);
+
+    let res = match &request.command {
+        Command::SwitchSchedule(switch) => reader.switch_schedule(switch),
+        Command::Sleep => reader.sleep(),
+        Command::Wakeup => reader.wakeup(),
+    };
+
+    if let Err(err) = &res {
+        let name = reader.name();
+
+        log::error!("{name} command failed - {err:?}");
+    }
+
+    let _ = request.reply_tx.send(res);
+}
+
 async fn read_and_forward_lin_frame(
     reader: &mut impl FrameReader,
     vbus: &socketcan::tokio::CanSocket,
 ) -> Result<()> {
-    if let Some(frame) = reader.try_read() {
+    // Forward everything that is ready, a notified tick may stand for several frames
+    while let Some(mut frame) = reader.try_read() {
         let name = reader.name();
 
         log::debug!("{name} Read LIN bus frame {frame:?}");
 
+        let error_report = frame.error_report();
+
+        if let Some(info) = &frame.info
+            && info.flags.is_error()
+        {
+            log::warn!("{name} LIN error {:?} on frame {frame:?}", info.flags);
+
+            // A response received with errors is not valid, only the header is forwarded
+            frame.msg.clear();
+        }
+
         if let Some(frame) = socketcan::CanDataFrame::from_raw_id(frame.id, &frame.msg) {
             log::debug!("{name} Sent frame={frame:?}");
 
@@ -170,6 +497,42 @@ async fn read_and_forward_lin_frame(
         } else {
             log::error!("{name} Failed to build frame from {frame:?}");
         }
+
+        if let Some(report) = error_report {
+            write_error_frame(name, vbus, frame::LIN_ERROR_CLASS, &report).await?;
+        }
+    }
+
+    // Outages, health and sleep changes are reported on the vbus, the bus keeps running meanwhile
+    while let Some(event) = reader.try_read_event() {
+        let (class, data) = match event {
+            BusEvent::Lost { .. } => (frame::BUS_LOST_CLASS, vec![]),
+            BusEvent::Restored => (frame::BUS_RESTORED_CLASS, vec![]),
+            BusEvent::Health(health) => (frame::HEALTH_CLASS, vec![health.code()]),
+            BusEvent::Asleep => (frame::SLEEP_CLASS, vec![1]),
+            BusEvent::Awake => (frame::SLEEP_CLASS, vec![0]),
+        };
+
+        write_error_frame(reader.name(), vbus, class, &data).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_error_frame(
+    name: &str,
+    vbus: &socketcan::tokio::CanSocket,
+    class: u32,
+    data: &[u8],
+) -> Result<()> {
+    match socketcan::CanErrorFrame::new_error(class, data) {
+        Ok(error_frame) => {
+            log::debug!("{name} Sent error frame={error_frame:?}");
+
+            vbus.write_frame(socketcan::CanFrame::Error(error_frame))
+                .await?;
+        }
+        Err(err) => log::error!("{name} Failed to build error frame {err}"),
     }
 
     Ok(())
@@ -185,7 +548,7 @@ async fn read_and_forward_network_master_frame(
         Ok(packet) => {
             let frame = packet.frame;
 
-            if frame.msg.is_empty() {
+            if frame.msg.is_empty() && !frame.is_wakeup() {
                 log::debug!("{name} master requests update for frame id {}", frame.id);
 
                 master.request_update(frame.id)